@@ -1,11 +1,17 @@
-use crate::Error;
+use crate::{
+    settings::{AudioCodec, EncoderConfig, Speakers},
+    Error,
+};
 use ffmpeg_next as ffmpeg;
 use godot::engine::audio_server::SpeakerMode;
 use std::{ffi::c_void, mem::size_of};
 
-pub struct OpusEncoder {
+/// Encodes the interleaved float samples handed over by Godot as Opus, FLAC
+/// or AAC, whichever the container resolved to
+pub struct AudioEncoder {
     pub encoder: ffmpeg::encoder::Audio,
     pub codec: ffmpeg::Codec,
+    audio_codec: AudioCodec,
     sample_rate: u32,
     channels: u16,
     frame_size: usize,
@@ -15,19 +21,83 @@ pub struct OpusEncoder {
 pub const OPUS_SAMPLE_RATE: u32 = 48_000;
 pub const OPUS_FRAME_SIZE: usize = 960;
 pub const STEREO_CHANNELS: u16 = 2;
+pub const OPUS_BITRATE: u32 = 128_000;
+pub const AAC_BITRATE: u32 = 192_000;
 
-impl OpusEncoder {
-    pub fn new(sample_rate: u32, speaker_mode: SpeakerMode, config: &crate::settings::EncoderConfig) -> Result<Self, Error> {
-        let channels = match speaker_mode {
-            SpeakerMode::STEREO => 2,
-            SpeakerMode::SURROUND_31 => 4,
-            SpeakerMode::SURROUND_51 => 6,
-            SpeakerMode::SURROUND_71 => 8,
-            _ => 2,
-        };
+/// Nominal stereo bitrate of the lossy codecs, FLAC has none
+pub fn bitrate(codec: AudioCodec) -> Option<u32> {
+    match codec {
+        AudioCodec::Opus => Some(OPUS_BITRATE),
+        AudioCodec::Aac => Some(AAC_BITRATE),
+        AudioCodec::Flac => None,
+    }
+}
+
+/// Bitrate for `channels`, surround gets the same bits per channel as stereo
+pub fn stream_bitrate(codec: AudioCodec, channels: u16) -> Option<u32> {
+    bitrate(codec).map(|bitrate| bitrate / STEREO_CHANNELS as u32 * channels as u32)
+}
+
+/// Rate Godot is asked to mix at, 0 turns audio capture off
+pub fn mix_rate(config: &EncoderConfig) -> u32 {
+    if config.enable_audio {
+        OPUS_SAMPLE_RATE
+    } else {
+        0
+    }
+}
+
+/// Speaker mode Godot is asked to mix in
+pub fn speaker_mode(speakers: Speakers) -> SpeakerMode {
+    match speakers {
+        Speakers::Stereo => SpeakerMode::STEREO,
+        Speakers::Surround51 => SpeakerMode::SURROUND_51,
+        Speakers::Surround71 => SpeakerMode::SURROUND_71,
+    }
+}
+
+/// Number of interleaved channels Godot mixes for `speaker_mode`
+pub fn channel_count(speaker_mode: SpeakerMode) -> u16 {
+    match speaker_mode {
+        SpeakerMode::SURROUND_31 => 4,
+        SpeakerMode::SURROUND_51 => 6,
+        SpeakerMode::SURROUND_71 => 8,
+        _ => STEREO_CHANNELS,
+    }
+}
+
+/// Layout matching the channel order Godot mixes in for a given channel count.
+/// Godot interleaves in FFmpeg's native order (FL, FR, FC, LFE, BL, BR, then
+/// SL, SR), libopus reorders it to the Vorbis order (FL, FC, FR, RL, RR, LFE)
+/// mapping family 1 stores by itself.
+pub fn channel_layout(channels: u16) -> Option<ffmpeg::channel_layout::ChannelLayout> {
+    match channels {
+        1 => Some(ffmpeg::channel_layout::ChannelLayout::MONO),
+        2 => Some(ffmpeg::channel_layout::ChannelLayout::STEREO),
+        4 => Some(ffmpeg::channel_layout::ChannelLayout::_3POINT1),
+        6 => Some(ffmpeg::channel_layout::ChannelLayout::_5POINT1_BACK),
+        8 => Some(ffmpeg::channel_layout::ChannelLayout::_7POINT1),
+        _ => None,
+    }
+}
+
+impl AudioEncoder {
+    pub fn new(
+        sample_rate: u32,
+        speaker_mode: SpeakerMode,
+        config: &crate::settings::EncoderConfig,
+    ) -> Result<Self, Error> {
+        let channels = channel_count(speaker_mode);
 
-        let codec = ffmpeg::encoder::find(ffmpeg::codec::Id::OPUS)
-            .ok_or_else(|| Error::Encoding("Opus codec not found".to_string()))?;
+        let codec = ffmpeg::encoder::find(config.audio_codec.id())
+            .ok_or_else(|| Error::Encoding(format!("{:?} codec not found", config.audio_codec)))?;
+
+        // Only Opus takes interleaved floats as-is, the rest are converted in `encode_audio_data`
+        let sample_format = match config.audio_codec {
+            AudioCodec::Opus => ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Packed),
+            AudioCodec::Flac => ffmpeg::format::Sample::I32(ffmpeg::format::sample::Type::Packed),
+            AudioCodec::Aac => ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Planar),
+        };
 
         let mut encoder = ffmpeg::codec::context::Context::new_with_codec(codec)
             .encoder()
@@ -35,46 +105,78 @@ impl OpusEncoder {
             .map_err(|e| Error::Encoding(format!("Could not create audio encoder context: {e}")))?;
 
         encoder.set_rate(sample_rate as i32);
-        encoder.set_format(ffmpeg::format::Sample::F32(
-            ffmpeg::format::sample::Type::Packed,
-        ));
-        let channel_layout = match channels {
-            1 => ffmpeg::channel_layout::ChannelLayout::MONO,
-            2 => ffmpeg::channel_layout::ChannelLayout::STEREO,
-            4 => ffmpeg::channel_layout::ChannelLayout::QUAD,
-            6 => ffmpeg::channel_layout::ChannelLayout::_5POINT1,
-            8 => ffmpeg::channel_layout::ChannelLayout::_7POINT1,
-            _ => {
-                return Err(Error::Encoding(
-                    "Unsupported channel count for Opus".to_string(),
-                ))
-            }
-        };
+        encoder.set_time_base((1, sample_rate as i32));
+        encoder.set_format(sample_format);
+        let channel_layout = channel_layout(channels).ok_or_else(|| {
+            Error::Encoding(format!("Unsupported channel count {channels} for audio"))
+        })?;
         encoder.set_channel_layout(channel_layout);
 
         let mut dict = ffmpeg::Dictionary::new();
-        
-        // Apply encoder config settings for audio quality
-        let compression_level = match config.quality {
-            crate::settings::Quality::Realtime => "10", // Fastest encoding
-            crate::settings::Quality::Good => "5",      // Balanced quality/speed
-            crate::settings::Quality::Best => "0",      // Highest quality
-        };
-        dict.set("compression_level", compression_level);
-        dict.set("application", "audio");
-        dict.set("vbr", "on");
-        dict.set("bitrate", "128000");
+        let bitrate = stream_bitrate(config.audio_codec, channels).unwrap_or_default();
 
-        let encoder = encoder
-            .open_as_with(ffmpeg::codec::Id::OPUS, dict)
-            .map_err(|e| Error::Encoding(format!("Failed to open Opus encoder: {e}")))?;
+        match config.audio_codec {
+            AudioCodec::Opus => {
+                // Apply encoder config settings for audio quality
+                let compression_level = match config.quality {
+                    crate::settings::Quality::Realtime => "10", // Fastest encoding
+                    crate::settings::Quality::Good => "5",      // Balanced quality/speed
+                    crate::settings::Quality::Best => "0",      // Highest quality
+                };
+                dict.set("compression_level", compression_level);
+                dict.set("application", "audio");
+                dict.set("vbr", "on");
+                dict.set("bitrate", &bitrate.to_string());
+                // Family 0 only holds mono and stereo, family 1 is the
+                // surround mapping browsers and players decode
+                if channels > STEREO_CHANNELS {
+                    dict.set("mapping_family", "1");
+                }
+            }
+            AudioCodec::Flac => {
+                let compression_level = match config.quality {
+                    crate::settings::Quality::Realtime => "0",
+                    crate::settings::Quality::Good => "5",
+                    crate::settings::Quality::Best => "8",
+                };
+                dict.set("compression_level", compression_level);
+            }
+            AudioCodec::Aac => {
+                let coder = match config.quality {
+                    crate::settings::Quality::Realtime => "fast",
+                    crate::settings::Quality::Good | crate::settings::Quality::Best => "twoloop",
+                };
+                dict.set("aac_coder", coder);
+                dict.set("b", &bitrate.to_string());
+            }
+        }
 
-        // if sample rate is unexpected
-        let frame_size = OPUS_FRAME_SIZE * sample_rate as usize / OPUS_SAMPLE_RATE as usize;
+        unsafe {
+            crate::options::merge(
+                &mut dict,
+                encoder.as_mut_ptr() as *mut c_void,
+                &config.audio_options,
+                codec.name(),
+            );
+        }
+
+        let encoder = encoder.open_as_with(codec, dict).map_err(|e| {
+            Error::Encoding(format!(
+                "Failed to open {:?} encoder: {e}",
+                config.audio_codec
+            ))
+        })?;
+
+        let frame_size = match config.audio_codec {
+            // if sample rate is unexpected
+            AudioCodec::Opus => OPUS_FRAME_SIZE * sample_rate as usize / OPUS_SAMPLE_RATE as usize,
+            AudioCodec::Flac | AudioCodec::Aac => encoder.frame_size() as usize,
+        };
 
-        Ok(OpusEncoder {
+        Ok(AudioEncoder {
             codec,
             encoder,
+            audio_codec: config.audio_codec,
             sample_rate,
             channels,
             frame_size,
@@ -82,6 +184,11 @@ impl OpusEncoder {
         })
     }
 
+    /// Number of interleaved samples, across all channels, consumed per encoded frame
+    pub fn samples_per_frame(&self) -> usize {
+        self.frame_size * self.channels as usize
+    }
+
     pub fn encode_audio_data(
         &mut self,
         audio_data: *const c_void,
@@ -97,17 +204,47 @@ impl OpusEncoder {
         }
 
         let mut frame = ffmpeg::frame::Audio::new(
-            ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Packed),
+            self.encoder.format(),
             samples_per_channel,
             self.encoder.channel_layout(),
         );
 
         frame.set_rate(self.sample_rate);
 
-        unsafe {
-            let frame_data = frame.data_mut(0);
-            let audio_bytes = std::slice::from_raw_parts(audio_data as *const u8, data_size);
-            frame_data[..data_size].copy_from_slice(audio_bytes);
+        match self.audio_codec {
+            AudioCodec::Opus => unsafe {
+                let frame_data = frame.data_mut(0);
+                let audio_bytes = std::slice::from_raw_parts(audio_data as *const u8, data_size);
+                frame_data[..data_size].copy_from_slice(audio_bytes);
+            },
+            AudioCodec::Flac => {
+                let samples = unsafe {
+                    std::slice::from_raw_parts(
+                        audio_data as *const f32,
+                        data_size / size_of::<f32>(),
+                    )
+                };
+                let frame_data = frame.data_mut(0);
+                for (dst, &sample) in frame_data.chunks_exact_mut(size_of::<i32>()).zip(samples) {
+                    let sample = (sample.clamp(-1.0, 1.0) as f64 * i32::MAX as f64) as i32;
+                    dst.copy_from_slice(&sample.to_ne_bytes());
+                }
+            }
+            AudioCodec::Aac => {
+                let samples = unsafe {
+                    std::slice::from_raw_parts(
+                        audio_data as *const f32,
+                        data_size / size_of::<f32>(),
+                    )
+                };
+                let channels = self.channels as usize;
+                for channel in 0..channels {
+                    let plane = frame.plane_mut::<f32>(channel);
+                    for (dst, interleaved) in plane.iter_mut().zip(samples.chunks_exact(channels)) {
+                        *dst = interleaved[channel];
+                    }
+                }
+            }
         }
 
         let pts = self.frame_count * self.frame_size as u64;
@@ -148,3 +285,126 @@ impl OpusEncoder {
         self.encoder.time_base()
     }
 }
+
+/// Decides how many samples belong to each movie frame. Godot mixes a fixed
+/// `mix_rate / fps` samples per frame, rounded down, so at rates like 144 or
+/// 165 fps the audio would fall behind the video by a few seconds an hour.
+/// The fractional remainder is accumulated instead, giving each frame one
+/// sample more whenever a whole one has built up.
+pub struct AudioClock {
+    mix_rate: u64,
+    fps: u64,
+    /// Movie frames handed out so far
+    frames: u64,
+    /// Samples per channel handed out so far
+    samples: u64,
+}
+
+impl AudioClock {
+    pub fn new(mix_rate: u32, fps: u32) -> Self {
+        Self {
+            mix_rate: mix_rate as u64,
+            fps: fps.max(1) as u64,
+            frames: 0,
+            samples: 0,
+        }
+    }
+
+    /// Samples per channel in the block Godot passes to `write_frame`
+    pub fn block_len(&self) -> usize {
+        (self.mix_rate / self.fps) as usize
+    }
+
+    /// Samples per channel that belong to the next movie frame, `block_len`
+    /// or one more
+    pub fn next_frame(&mut self) -> usize {
+        self.frames += 1;
+        let due = self.frames * self.mix_rate / self.fps;
+        let len = due - self.samples;
+        self.samples = due;
+        len as usize
+    }
+}
+
+/// Pads or cuts interleaved `samples` to `len` samples per channel, padding
+/// by holding the last sample so the extra one can't click
+pub fn fit_to_len(mut samples: Vec<f32>, len: usize, channels: usize) -> Vec<f32> {
+    let target = len * channels;
+    if samples.len() >= target {
+        samples.truncate(target);
+        return samples;
+    }
+
+    let last = if samples.len() >= channels {
+        samples[samples.len() - channels..].to_vec()
+    } else {
+        vec![0.0; channels]
+    };
+    while samples.len() < target {
+        samples.extend_from_slice(&last);
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR_SECONDS: u64 = 60 * 60;
+    /// Audio may lead or trail the video by less than one sample
+    const DRIFT_BUDGET_SAMPLES: f64 = 1.0;
+
+    #[test]
+    fn audio_stays_in_sync_over_an_hour() {
+        for fps in [24, 25, 30, 50, 60, 70, 90, 120, 144, 165, 240] {
+            let mut clock = AudioClock::new(OPUS_SAMPLE_RATE, fps);
+            let mut samples = 0u64;
+            let mut worst = 0.0f64;
+
+            for frame in 1..=HOUR_SECONDS * fps as u64 {
+                let len = clock.next_frame();
+                assert!(
+                    len == clock.block_len() || len == clock.block_len() + 1,
+                    "{fps} fps frame {frame} got {len} samples"
+                );
+                samples += len as u64;
+
+                let video_samples = frame as f64 * OPUS_SAMPLE_RATE as f64 / fps as f64;
+                worst = worst.max((video_samples - samples as f64).abs());
+            }
+
+            assert!(
+                worst < DRIFT_BUDGET_SAMPLES,
+                "{fps} fps drifted by {worst} samples"
+            );
+            assert_eq!(samples, HOUR_SECONDS * OPUS_SAMPLE_RATE as u64);
+        }
+    }
+
+    #[test]
+    fn truncating_block_sizes_exceed_the_budget() {
+        // What a fixed `mix_rate / fps` per frame loses in an hour at 144 fps
+        let fps = 144;
+        let block = AudioClock::new(OPUS_SAMPLE_RATE, fps).block_len() as u64;
+        let lost = HOUR_SECONDS * OPUS_SAMPLE_RATE as u64 - HOUR_SECONDS * fps as u64 * block;
+        assert!(lost as f64 > DRIFT_BUDGET_SAMPLES);
+    }
+
+    #[test]
+    fn short_blocks_hold_the_last_sample() {
+        let block = vec![0.1, -0.1, 0.2, -0.2];
+        assert_eq!(
+            fit_to_len(block, 3, 2),
+            vec![0.1, -0.1, 0.2, -0.2, 0.2, -0.2]
+        );
+    }
+
+    #[test]
+    fn long_blocks_are_cut() {
+        assert_eq!(
+            fit_to_len(vec![0.1, -0.1, 0.2, -0.2], 1, 2),
+            vec![0.1, -0.1]
+        );
+        assert!(fit_to_len(Vec::new(), 0, 2).is_empty());
+    }
+}
@@ -0,0 +1,74 @@
+//! Region of the movie that gets recorded. It starts at the static
+//! `capture_rect` setting and follows `set_capture_rect`, easing towards each
+//! new rect so pans and zooms don't jump.
+use godot::prelude::*;
+
+pub struct CaptureRect {
+    /// Where the region is headed, `None` for the whole frame
+    target: Option<Rect2i>,
+    /// Region sampled for the previous frame, fractional while easing
+    current: Option<Rect2>,
+    /// Seconds to cover roughly two thirds of the way to a new target
+    smoothing: f64,
+}
+
+impl CaptureRect {
+    pub fn new(rect: Option<Rect2i>, smoothing: f64) -> Self {
+        Self {
+            target: rect,
+            current: None,
+            smoothing,
+        }
+    }
+
+    /// Region the next frames move towards, an empty rect means the whole frame
+    pub fn set_target(&mut self, rect: Rect2i) {
+        self.target = (rect.size.x > 0 && rect.size.y > 0).then_some(rect);
+    }
+
+    /// Size of the region when recording starts, which fixes the output size
+    pub fn initial_size(&self, frame_size: Vector2i) -> Vector2i {
+        self.target
+            .map_or(frame_size, |rect| clamp_to_frame(rect, frame_size).size)
+    }
+
+    /// Advances the easing by one frame and returns the region to sample,
+    /// kept inside a frame of `frame_size`
+    pub fn next(&mut self, frame_size: Vector2i, fps: f64) -> Rect2 {
+        let target = self
+            .target
+            .map_or(Rect2i::new(Vector2i::ZERO, frame_size), |rect| {
+                clamp_to_frame(rect, frame_size)
+            });
+        let target = Rect2::new(
+            Vector2::new(target.position.x as f32, target.position.y as f32),
+            Vector2::new(target.size.x as f32, target.size.y as f32),
+        );
+
+        let rect = match self.current {
+            Some(current) if self.smoothing > 0.0 => {
+                let t = 1.0 - (-1.0 / (fps * self.smoothing)).exp() as f32;
+                Rect2::new(
+                    current.position + (target.position - current.position) * t,
+                    current.size + (target.size - current.size) * t,
+                )
+            }
+            _ => target,
+        };
+        self.current = Some(rect);
+        rect
+    }
+}
+
+/// Moves and shrinks `rect` until it lies within the frame, at least one pixel big
+fn clamp_to_frame(rect: Rect2i, frame_size: Vector2i) -> Rect2i {
+    let size = Vector2i::new(
+        rect.size.x.clamp(1, frame_size.x.max(1)),
+        rect.size.y.clamp(1, frame_size.y.max(1)),
+    );
+    let position = Vector2i::new(
+        rect.position.x.clamp(0, (frame_size.x - size.x).max(0)),
+        rect.position.y.clamp(0, (frame_size.y - size.y).max(0)),
+    );
+    Rect2i::new(position, size)
+}
@@ -0,0 +1,158 @@
+use std::path::PathBuf;
+
+use crate::{
+    audio,
+    settings::{AudioCodec, BitDepth, ChromaSubsampling, EncoderConfig, Transfer, VideoCodec},
+    Error,
+};
+
+/// Output containers Sorkin can write, picked from the file extension
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    WebM,
+    Matroska,
+    Mp4,
+    QuickTime,
+}
+
+impl Container {
+    pub fn from_path(path: &str) -> Option<Self> {
+        let path: PathBuf = path.into();
+        match path.extension().and_then(|s| s.to_str()) {
+            Some("webm") => Some(Container::WebM),
+            Some("mkv") => Some(Container::Matroska),
+            Some("mp4") => Some(Container::Mp4),
+            Some("mov") => Some(Container::QuickTime),
+            _ => None,
+        }
+    }
+
+    pub fn is_mp4_family(self) -> bool {
+        matches!(self, Container::Mp4 | Container::QuickTime)
+    }
+
+    /// Whether the linked FFmpeg can produce video this container accepts
+    pub fn is_available(self) -> bool {
+        if self.is_mp4_family() {
+            ffmpeg_next::init().is_ok() && VideoCodec::H264.find_encoder().is_some()
+        } else {
+            true
+        }
+    }
+
+    /// Picks the codecs actually used for this container. The codec settings
+    /// describe WebM/Matroska output, MP4 and MOV always carry H.264 video
+    /// with the audio codec from `mp4_audio_codec`.
+    pub fn resolve(self, config: &EncoderConfig) -> EncoderConfig {
+        let config = if self.is_mp4_family() {
+            EncoderConfig {
+                video_codec: VideoCodec::H264,
+                audio_codec: config.mp4_audio_codec,
+                ..config.clone()
+            }
+        } else {
+            config.clone()
+        };
+        // Depend on the codec picked above
+        config.with_pixel_art().with_aligned_keyframes()
+    }
+
+    /// Rejects codec combinations the container cannot hold
+    pub fn check_config(self, config: &EncoderConfig) -> Result<(), Error> {
+        let config = self.resolve(config);
+
+        // e.g. AV1 selected while the linked FFmpeg has no AV1 encoder
+        if config.video_codec.find_encoder().is_none() {
+            return Err(Error::Encoding(format!(
+                "No {:?} encoder in this FFmpeg build",
+                config.video_codec
+            )));
+        }
+
+        if self == Container::WebM && !config.video_codec.webm_compatible() {
+            return Err(Error::Encoding(format!(
+                "{:?} video is not allowed in WebM, write to a .mkv file instead",
+                config.video_codec
+            )));
+        }
+
+        if self == Container::WebM && config.enable_audio && !config.audio_codec.webm_compatible() {
+            return Err(Error::Encoding(format!(
+                "{:?} audio is not allowed in WebM, write to a .mkv file instead",
+                config.audio_codec
+            )));
+        }
+
+        // FFmpeg's mov muxer only writes Opus in MP4 mode
+        if self == Container::QuickTime
+            && config.enable_audio
+            && config.audio_codec == AudioCodec::Opus
+        {
+            return Err(Error::Encoding(
+                "Opus audio is not allowed in .mov, set mp4_audio_codec to AAC or write to a .mp4 file"
+                    .to_string(),
+            ));
+        }
+
+        if config.target_size_mb > 0.0 {
+            // the rate control settings are replaced by a bitrate picked in `write_end`
+            if config.video_codec == VideoCodec::Ffv1 {
+                return Err(Error::Encoding(
+                    "FFV1 is lossless and cannot target a file size".to_string(),
+                ));
+            }
+
+            if config.enable_audio && audio::bitrate(config.audio_codec).is_none() {
+                return Err(Error::Encoding(format!(
+                    "{:?} audio has no fixed bitrate and cannot target a file size",
+                    config.audio_codec
+                )));
+            }
+        } else {
+            config.validate_rate_control()?;
+        }
+
+        config.validate_keyframe_interval()?;
+
+        if config.bit_depth == BitDepth::Ten && config.video_codec == VideoCodec::H264 {
+            return Err(Error::Encoding(
+                "10-bit output needs VP9, AV1 or FFV1, H.264 is 8-bit only".to_string(),
+            ));
+        }
+
+        if config.chroma_subsampling == ChromaSubsampling::Yuv444
+            && !config.video_codec.supports_yuv444()
+        {
+            return Err(Error::Encoding(
+                "4:4:4 chroma needs VP9, FFV1 or AV1 with libaom, H.264 and SVT-AV1 are 4:2:0 only"
+                    .to_string(),
+            ));
+        }
+
+        if config.transfer != Transfer::Sdr && config.bit_depth != BitDepth::Ten {
+            return Err(Error::Encoding(format!(
+                "{:?} HDR output requires 10-bit",
+                config.transfer
+            )));
+        }
+
+        if config.two_pass && config.video_codec != VideoCodec::Vp9 {
+            return Err(Error::Encoding(format!(
+                "Two-pass encoding is only supported with VP9, not {:?}",
+                config.video_codec
+            )));
+        }
+
+        if config.alpha_channel
+            && !config.alpha_sidecar
+            && !matches!(config.video_codec, VideoCodec::Vp9 | VideoCodec::Ffv1)
+        {
+            return Err(Error::Encoding(format!(
+                "Embedded alpha requires VP9 or FFV1, enable alpha_sidecar to record alpha with {:?}",
+                config.video_codec
+            )));
+        }
+
+        Ok(())
+    }
+}
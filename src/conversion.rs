@@ -1,18 +1,33 @@
 use ffmpeg_next::format::Pixel;
+use ffmpeg_next::{Rational, Rescale};
 use godot::classes::image::Format;
 use godot::engine::rendering_device::{
     DataFormat, SamplerFilter, ShaderStage, TextureUsageBits, UniformType,
 };
 
-use crate::Error;
+use crate::{
+    cpu_conversion::{self, Pixels, Placement},
+    settings::{ChromaFilter, ColorRange, EncoderConfig, FitMode, ScaleFilter, Transfer},
+    Error,
+};
 use godot::classes::{Image, RenderingServer};
 use godot::engine::{RdSamplerState, RdUniform, RenderingDevice};
 use godot::prelude::*;
 
 /// Converts frame index to a presentation time stamp
-pub fn frame_to_pts(frame_idx: i64, fps: i64, ticks_per_second: i64) -> i64 {
-    let seconds = frame_idx as f64 / fps as f64;
-    (seconds * ticks_per_second as f64).round() as i64
+pub fn frame_to_pts(frame_idx: i64, fps: Rational, time_base: Rational) -> i64 {
+    frame_idx.rescale(fps.invert(), time_base)
+}
+
+/// Number of output frames at `fps` that start within the first
+/// `movie_frames` movie frames at `movie_fps`
+pub fn output_frames_due(movie_frames: u64, movie_fps: u32, fps: Rational) -> u64 {
+    (movie_frames * fps.numerator() as u64).div_ceil(movie_fps as u64 * fps.denominator() as u64)
+}
+
+/// Time base of the video streams, a thousandth of a frame
+pub fn video_time_base(fps: Rational) -> Rational {
+    Rational::new(fps.denominator(), fps.numerator() * 1000)
 }
 
 /// Texture containers
@@ -29,23 +44,393 @@ pub enum Channels {
     },
 }
 
-pub struct ConversionContext {
+/// Whether Godot hands over linear float colors, as HDR viewports do
+fn is_float_format(format: Format) -> bool {
+    matches!(
+        format,
+        Format::RF
+            | Format::RGF
+            | Format::RGBF
+            | Format::RGBAF
+            | Format::RH
+            | Format::RGH
+            | Format::RGBH
+            | Format::RGBAH
+            | Format::RGBE9995
+    )
+}
+
+/// Whether samples of `format` are stored as 16 bit little endian words
+pub fn is_ten_bit(format: Pixel) -> bool {
+    matches!(
+        format,
+        Pixel::YUV420P10LE | Pixel::YUV444P10LE | Pixel::YUVA420P10LE | Pixel::YUVA444P10LE
+    )
+}
+
+/// Fills the chroma planes of an alpha frame, which only carries the
+/// matte in its luma plane, with neutral gray
+pub fn fill_neutral_chroma(frame: &mut ffmpeg_next::util::frame::Video) {
+    let ten_bit = is_ten_bit(frame.format());
+    for plane in 1..frame.planes() {
+        let data = frame.data_mut(plane);
+        if ten_bit {
+            for sample in data.chunks_exact_mut(2) {
+                sample.copy_from_slice(&512u16.to_le_bytes());
+            }
+        } else {
+            data.fill(128);
+        }
+    }
+}
+
+/// Copies `frame` into a larger frame, repeating its last column and row
+/// into the added space. For encoders that only take even sizes
+pub fn pad_frame(
+    frame: &ffmpeg_next::util::frame::Video,
+    width: u32,
+    height: u32,
+) -> ffmpeg_next::util::frame::Video {
+    let mut padded = ffmpeg_next::util::frame::Video::new(frame.format(), width, height);
+    padded.set_pts(frame.pts());
+    padded.set_kind(frame.kind());
+
+    let bytes_per_sample = if is_ten_bit(frame.format()) { 2 } else { 1 };
+    for plane in 0..frame.planes() {
+        let src_width = frame.plane_width(plane) as usize * bytes_per_sample;
+        let src_height = frame.plane_height(plane) as usize;
+        let dst_width = padded.plane_width(plane) as usize * bytes_per_sample;
+        let dst_height = padded.plane_height(plane) as usize;
+        let src_stride = frame.stride(plane);
+        let dst_stride = padded.stride(plane);
+        let src = frame.data(plane);
+        let dst = padded.data_mut(plane);
+
+        for row in 0..dst_height {
+            let src_row = &src[row.min(src_height - 1) * src_stride..][..src_width];
+            let dst_row = &mut dst[row * dst_stride..][..dst_width];
+            dst_row[..src_width].copy_from_slice(src_row);
+            let last = &src_row[src_width - bytes_per_sample..];
+            for sample in dst_row[src_width..].chunks_exact_mut(bytes_per_sample) {
+                sample.copy_from_slice(last);
+            }
+        }
+    }
+
+    padded
+}
+
+/// Copies `frame` into a `format` frame with a fourth plane taken from the
+/// luma of `alpha_frame`, for codecs that store alpha natively
+pub fn merge_alpha(
+    frame: &ffmpeg_next::util::frame::Video,
+    alpha_frame: &ffmpeg_next::util::frame::Video,
+    format: Pixel,
+) -> ffmpeg_next::util::frame::Video {
+    let mut merged = ffmpeg_next::util::frame::Video::new(format, frame.width(), frame.height());
+    merged.set_pts(frame.pts());
+    merged.set_kind(frame.kind());
+
+    let bytes_per_sample = if is_ten_bit(format) { 2 } else { 1 };
+    let sources = [(frame, 0), (frame, 1), (frame, 2), (alpha_frame, 0)];
+    for (plane, (source, source_plane)) in sources.into_iter().enumerate() {
+        let row_bytes = merged.plane_width(plane) as usize * bytes_per_sample;
+        let src_stride = source.stride(source_plane);
+        let dst_stride = merged.stride(plane);
+        for row in 0..merged.plane_height(plane) as usize {
+            let src_row = &source.data(source_plane)[row * src_stride..][..row_bytes];
+            merged.data_mut(plane)[row * dst_stride..][..row_bytes].copy_from_slice(src_row);
+        }
+    }
+
+    merged
+}
+
+/// Inserts `#define`s right after the `#version` line of a shader
+fn shader_source(source: &str, defines: &[&str]) -> String {
+    let (version, body) = source.split_once('\n').unwrap_or((source, ""));
+    let defines: String = defines
+        .iter()
+        .map(|define| format!("#define {define}\n"))
+        .collect();
+    format!("{version}\n{defines}{body}")
+}
+
+/// Compiles a compute shader from `glsl/` with the given `#define`s
+fn compile_shader(
+    rd: &mut Gd<RenderingDevice>,
+    source: &str,
+    defines: &[&str],
+) -> Result<Rid, Error> {
+    let mut src = godot::classes::RdShaderSource::new_gd();
+    src.set_stage_source(ShaderStage::COMPUTE, shader_source(source, defines).into());
+
+    let spirv = rd
+        .shader_compile_spirv_from_source(src)
+        .ok_or(Error::Conversion("failed to compile source".into()))?;
+
+    Ok(rd.shader_create_from_spirv(spirv))
+}
+
+/// Movie size frames arrive at, and how the captured region of them is
+/// scaled to the encoded size
+#[derive(Clone, Copy)]
+struct Source {
+    width: u32,
+    height: u32,
+    filter: ScaleFilter,
+    fit_mode: FitMode,
+    /// Pad color in the input's encoding
+    pad: [f32; 4],
+}
+
+impl Source {
+    /// Fits `region` into a `width` x `height` output
+    fn placement(&self, region: Rect2, width: u32, height: u32) -> Placement {
+        let output = Vector2::new(width as f32, height as f32);
+        let scale = output / region.size;
+        let (source, dest) = match self.fit_mode {
+            FitMode::Stretch => (region, Rect2::new(Vector2::ZERO, output)),
+            FitMode::Crop => {
+                let size = output / scale.x.max(scale.y);
+                let position = region.position + (region.size - size) / 2.0;
+                (
+                    Rect2::new(position, size),
+                    Rect2::new(Vector2::ZERO, output),
+                )
+            }
+            FitMode::Pad => {
+                // Whole pixels keep the bars' edges sharp
+                let size = (region.size * scale.x.min(scale.y)).round();
+                let position = ((output - size) / 2.0).floor();
+                (region, Rect2::new(position, size))
+            }
+        };
+        Placement {
+            source,
+            dest,
+            pad: self.pad,
+        }
+    }
+
+    /// Whether the frame can be converted as is, without the scale pass
+    fn is_whole_frame(&self, placement: &Placement, width: u32, height: u32) -> bool {
+        let size = Vector2::new(width as f32, height as f32);
+        (self.width, self.height) == (width, height)
+            && placement.source == Rect2::new(Vector2::ZERO, size)
+            && placement.dest == placement.source
+    }
+}
+
+/// Where the color conversion runs, the compute shader needs a local
+/// RenderingDevice which headless and Compatibility renderer runs lack
+enum Backend {
+    Device(DeviceContext),
+    Cpu,
+}
+
+/// Resamples a region of the scratch texture to the output size ahead of the
+/// conversion
+struct ScalePass {
+    shader: Rid,
+    pipeline: Rid,
+    uniforms: Rid,
+    /// Output sized texture the conversion reads instead of the scratch texture
+    scaled: Rid,
+}
+
+struct DeviceContext {
     channels: Channels,
+    scale: ScalePass,
+    /// Conversion reading the scratch texture directly, when nothing is scaled
+    direct_uniforms: Rid,
+    source: Source,
     sampler: Rid,
-    pub width: u32,
-    pub height: u32,
+    width: u32,
+    height: u32,
+    ten_bit: bool,
+    chroma_444: bool,
     shader: Rid,
     device: Gd<RenderingDevice>,
     uniforms: Rid,
     pipeline: Rid,
 }
 
+pub struct ConversionContext {
+    backend: Backend,
+    /// Floats are passed on as is instead of being quantized to RGBA8
+    linear_input: bool,
+    output: cpu_conversion::Output,
+    source: Source,
+    /// Pixel format and size of the encoded frames
+    pub format: Pixel,
+    pub width: u32,
+    pub height: u32,
+}
+
 impl ConversionContext {
+    /// Size of the images `convert` expects
+    pub fn source_size(&self) -> (u32, u32) {
+        (self.source.width, self.source.height)
+    }
+
+    /// Converts `width` x `height` `from` images into `output` sized frames
+    /// as `config` describes them
+    pub fn new(
+        from: Format,
+        config: &EncoderConfig,
+        width: u32,
+        height: u32,
+        output: (u32, u32),
+    ) -> Result<Self, crate::Error> {
+        let to = config.pixel_format();
+        if !matches!(
+            to,
+            Pixel::YUVA420P
+                | Pixel::YUV420P
+                | Pixel::YUV420P10LE
+                | Pixel::YUV444P
+                | Pixel::YUV444P10LE
+        ) {
+            return Err(crate::Error::Conversion(format!(
+                "Unsupported Conversion {from:?} : {to:?}"
+            )));
+        }
+
+        let linear_input = is_float_format(from);
+        let (output_width, output_height) = output;
+        let output = cpu_conversion::Output {
+            ten_bit: is_ten_bit(to),
+            chroma_444: matches!(to, Pixel::YUV444P | Pixel::YUV444P10LE),
+            chroma_filter: config.chroma_filter,
+            transfer: config.transfer,
+            matrix: config.matrix(),
+            range: config.color_range,
+        };
+
+        let pad = config.pad_color;
+        let pad = if linear_input {
+            [
+                cpu_conversion::srgb_to_linear(pad.r),
+                cpu_conversion::srgb_to_linear(pad.g),
+                cpu_conversion::srgb_to_linear(pad.b),
+                pad.a,
+            ]
+        } else {
+            [pad.r, pad.g, pad.b, pad.a]
+        };
+        let source = Source {
+            width,
+            height,
+            filter: config.scale_filter,
+            fit_mode: config.fit_mode,
+            pad,
+        };
+        if (output_width, output_height) != (width, height) {
+            godot_print!(
+                "Scaling {width}x{height} to {output_width}x{output_height} ({:?})",
+                config.scale_filter
+            );
+        }
+
+        let backend = match RenderingServer::singleton().create_local_rendering_device() {
+            Some(rd) => Backend::Device(DeviceContext::new(
+                rd,
+                to,
+                linear_input,
+                output,
+                output_width,
+                output_height,
+                source,
+            )?),
+            None => {
+                godot_print!(
+                    "No local RenderingDevice available (headless or Compatibility renderer), converting frames on the CPU"
+                );
+                Backend::Cpu
+            }
+        };
+
+        Ok(Self {
+            backend,
+            linear_input,
+            output,
+            source,
+            format: to,
+            width: output_width,
+            height: output_height,
+        })
+    }
+
+    /// Converts `region` of `input_image`, in movie pixels, into `frame`,
+    /// fit to the output size as configured
+    pub fn convert(
+        &mut self,
+        mut input_image: Gd<Image>,
+        region: Rect2,
+        frame: &mut ffmpeg_next::util::frame::Video,
+        alpha_frame: Option<&mut ffmpeg_next::util::frame::Video>,
+    ) {
+        input_image.convert(if self.linear_input {
+            Format::RGBAF
+        } else {
+            Format::RGBA8
+        });
+        let placement = self.source.placement(region, self.width, self.height);
+
+        match &mut self.backend {
+            Backend::Device(device) => device.convert(input_image, &placement, frame, alpha_frame),
+            Backend::Cpu => {
+                let data = input_image.get_data();
+                let floats: Vec<f32>;
+                let mut pixels = if self.linear_input {
+                    floats = data
+                        .as_slice()
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                        .collect();
+                    Pixels::RgbaF(&floats)
+                } else {
+                    Pixels::Rgba8(data.as_slice())
+                };
+
+                let scaled: Vec<f32>;
+                if !self
+                    .source
+                    .is_whole_frame(&placement, self.width, self.height)
+                {
+                    scaled = cpu_conversion::resample(
+                        &pixels,
+                        (self.source.width, self.source.height),
+                        &placement,
+                        (self.width, self.height),
+                        self.source.filter,
+                        self.linear_input,
+                    );
+                    pixels = Pixels::RgbaF(&scaled);
+                }
+
+                cpu_conversion::rgba_to_yuv(
+                    pixels,
+                    self.linear_input,
+                    self.width,
+                    self.height,
+                    self.output,
+                    frame,
+                    alpha_frame,
+                );
+            }
+        }
+    }
+}
+
+impl DeviceContext {
     fn copy_plane_data(&mut self, texture: Rid, buf: &mut [u8], line_size: usize, divisor: u32) {
         let tex = self.device.texture_get_data(texture, 0);
         let tex_slice = tex.as_slice();
-        let plane_height = (self.height / divisor) as usize;
-        let plane_width = (self.width / divisor) as usize;
+        let bytes_per_sample = if self.ten_bit { 2 } else { 1 };
+        let plane_height = self.height.div_ceil(divisor) as usize;
+        let plane_width = self.width.div_ceil(divisor) as usize * bytes_per_sample;
 
         for row in 0..plane_height {
             let src_start = row * plane_width;
@@ -61,36 +446,59 @@ impl ConversionContext {
             }
         }
     }
-    pub fn new(from: Format, to: Pixel, width: u32, height: u32) -> Result<Self, crate::Error> {
-        let render_server = RenderingServer::singleton();
-
-        let mut rd = render_server
-            .create_local_rendering_device()
-            .ok_or(Error::Conversion(
-                "Sorkin does not work in headless mode or with GL renderer, use Forward+".into(),
-            ))?;
-
-        let mut src = godot::classes::RdShaderSource::new_gd();
-
-        src.set_stage_source(
-            ShaderStage::COMPUTE,
-            include_str!("./glsl/rgb_to_yuv420p.glsl").into(),
-        );
-
-        let spirv = rd
-            .shader_compile_spirv_from_source(src)
-            .ok_or(Error::Conversion("failed to compile source".into()))?;
+    fn new(
+        mut rd: Gd<RenderingDevice>,
+        to: Pixel,
+        linear_input: bool,
+        output: cpu_conversion::Output,
+        width: u32,
+        height: u32,
+        source: Source,
+    ) -> Result<Self, crate::Error> {
+        let mut defines = Vec::new();
+        if output.ten_bit {
+            defines.push("TEN_BIT");
+        }
+        if linear_input {
+            defines.push("LINEAR_INPUT");
+        }
+        match output.chroma_filter {
+            _ if output.chroma_444 => defines.push("CHROMA_444"),
+            ChromaFilter::Bilinear => defines.push("CHROMA_BILINEAR"),
+            ChromaFilter::Box => defines.push("CHROMA_BOX"),
+            ChromaFilter::Fast => {}
+        }
+        match output.transfer {
+            Transfer::Sdr => {}
+            Transfer::Pq => defines.push("HDR_PQ"),
+            Transfer::Hlg => defines.push("HDR_HLG"),
+        }
+        if output.range == ColorRange::Limited {
+            defines.push("LIMITED_RANGE");
+        }
+        let (kr, kb) = output.matrix.coefficients();
+        let coefficients = [format!("KR {kr:?}"), format!("KB {kb:?}")];
+        defines.extend(coefficients.iter().map(String::as_str));
 
-        let shader = rd.shader_create_from_spirv(spirv);
+        let shader = compile_shader(
+            &mut rd,
+            include_str!("./glsl/rgb_to_yuv420p.glsl"),
+            &defines,
+        )?;
 
         let pipeline = rd.compute_pipeline_create(shader);
 
+        let plane_format = if output.ten_bit {
+            DataFormat::R16_UNORM
+        } else {
+            DataFormat::R8_UNORM
+        };
         let data_tex_alloc = |rd: &mut Gd<RenderingDevice>, w, h, bind| {
             let mut view = godot::classes::RdTextureView::new_gd();
-            view.set_format_override(DataFormat::R8_UNORM);
+            view.set_format_override(plane_format);
 
             let mut fmt = godot::classes::RdTextureFormat::new_gd();
-            fmt.set_format(DataFormat::R8_UNORM);
+            fmt.set_format(plane_format);
             fmt.set_usage_bits(TextureUsageBits::STORAGE_BIT | TextureUsageBits::CAN_COPY_FROM_BIT);
             fmt.set_width(w);
             fmt.set_height(h);
@@ -108,7 +516,11 @@ impl ConversionContext {
             let default_view = godot::classes::RdTextureView::new_gd();
 
             let mut fmt = godot::classes::RdTextureFormat::new_gd();
-            fmt.set_format(DataFormat::R8G8B8A8_UNORM);
+            fmt.set_format(if linear_input {
+                DataFormat::R32G32B32A32_SFLOAT
+            } else {
+                DataFormat::R8G8B8A8_UNORM
+            });
             fmt.set_usage_bits(
                 TextureUsageBits::CAN_UPDATE_BIT
                     | TextureUsageBits::SAMPLING_BIT
@@ -136,25 +548,54 @@ impl ConversionContext {
         sampler_uni.set_uniform_type(UniformType::SAMPLER);
         sampler_uni.set_binding(5);
 
-        let (uniforms, channels) = match to {
-            Pixel::YUVA420P | Pixel::YUV420P => {
-                let (scratch_uni, scratch) = scratch_tex_alloc(&mut rd, width, height, 0);
+        let (uniforms, direct_uniforms, channels, scale) = match to {
+            Pixel::YUVA420P
+            | Pixel::YUV420P
+            | Pixel::YUV420P10LE
+            | Pixel::YUV444P
+            | Pixel::YUV444P10LE => {
+                let (scratch_uni, scratch) =
+                    scratch_tex_alloc(&mut rd, source.width, source.height, 0);
+                let scale = ScalePass::new(
+                    &mut rd,
+                    scratch,
+                    sampler,
+                    source.filter,
+                    linear_input,
+                    width,
+                    height,
+                )?;
+                let mut scaled_uni = RdUniform::new_gd();
+                scaled_uni.set_binding(0);
+                scaled_uni.set_uniform_type(UniformType::TEXTURE);
+                scaled_uni.add_id(scale.scaled);
                 let (y_uni, y) = data_tex_alloc(&mut rd, width, height, 1);
-                let (u_uni, u) = data_tex_alloc(&mut rd, width / 2, height / 2, 2);
-                let (v_uni, v) = data_tex_alloc(&mut rd, width / 2, height / 2, 3);
+                let (chroma_width, chroma_height) = if output.chroma_444 {
+                    (width, height)
+                } else {
+                    // Odd sizes get a last chroma sample covering a single column or row
+                    (width.div_ceil(2), height.div_ceil(2))
+                };
+                let (u_uni, u) = data_tex_alloc(&mut rd, chroma_width, chroma_height, 2);
+                let (v_uni, v) = data_tex_alloc(&mut rd, chroma_width, chroma_height, 3);
                 let (a_uni, a) = data_tex_alloc(&mut rd, width, height, 4);
 
-                let uniforms = Array::from(&[
-                    scratch_uni.clone(),
-                    y_uni.clone(),
-                    u_uni.clone(),
-                    v_uni.clone(),
-                    a_uni.clone(),
-                    sampler_uni.clone(),
-                ]);
-                let uniforms = rd.uniform_set_create(uniforms, shader, 0);
+                let mut uniform_set = |input: &Gd<RdUniform>| {
+                    let uniforms = Array::from(&[
+                        input.clone(),
+                        y_uni.clone(),
+                        u_uni.clone(),
+                        v_uni.clone(),
+                        a_uni.clone(),
+                        sampler_uni.clone(),
+                    ]);
+                    rd.uniform_set_create(uniforms, shader, 0)
+                };
+                let uniforms = uniform_set(&scaled_uni);
+                let direct_uniforms = uniform_set(&scratch_uni);
                 (
                     uniforms,
+                    direct_uniforms,
                     Channels::YUVA420p {
                         scratch,
                         y,
@@ -162,35 +603,40 @@ impl ConversionContext {
                         v,
                         a,
                     },
+                    scale,
                 )
             }
             _ => {
                 return Err(crate::Error::Conversion(format!(
-                    "Unsupported Conversion {from:?} : {to:?}"
+                    "Unsupported Conversion to {to:?}"
                 )))
             }
         };
 
         Ok(Self {
+            scale,
+            direct_uniforms,
+            source,
             sampler,
             uniforms,
             device: rd,
             width,
             height,
+            ten_bit: output.ten_bit,
+            chroma_444: output.chroma_444,
             channels,
             shader,
             pipeline,
         })
     }
 
-    pub fn convert(
+    fn convert(
         &mut self,
-        mut input_image: Gd<Image>,
+        input_image: Gd<Image>,
+        placement: &Placement,
         frame: &mut ffmpeg_next::util::frame::Video,
         alpha_frame: Option<&mut ffmpeg_next::util::frame::Video>,
     ) {
-        input_image.convert(Format::RGBA8);
-
         let Channels::YUVA420p { scratch, .. } = self.channels;
 
         self.device
@@ -198,11 +644,54 @@ impl ConversionContext {
 
         let compute_list = self.device.compute_list_begin();
 
+        let whole_frame = self
+            .source
+            .is_whole_frame(placement, self.width, self.height);
+        if !whole_frame {
+            let Placement { source, dest, pad } = *placement;
+            let params: PackedByteArray = [
+                source.position.x,
+                source.position.y,
+                source.size.x,
+                source.size.y,
+                dest.position.x,
+                dest.position.y,
+                dest.size.x,
+                dest.size.y,
+            ]
+            .into_iter()
+            .chain(pad)
+            .flat_map(f32::to_le_bytes)
+            .collect();
+
+            self.device
+                .compute_list_bind_compute_pipeline(compute_list, self.scale.pipeline);
+            self.device
+                .compute_list_bind_uniform_set(compute_list, self.scale.uniforms, 0);
+            self.device.compute_list_set_push_constant(
+                compute_list,
+                params.clone(),
+                params.len() as u32,
+            );
+            self.device.compute_list_dispatch(
+                compute_list,
+                self.width.div_ceil(16),
+                self.height.div_ceil(16),
+                1,
+            );
+            self.device.compute_list_add_barrier(compute_list);
+        }
+
         self.device
             .compute_list_bind_compute_pipeline(compute_list, self.pipeline);
 
+        let uniforms = if whole_frame {
+            self.direct_uniforms
+        } else {
+            self.uniforms
+        };
         self.device
-            .compute_list_bind_uniform_set(compute_list, self.uniforms, 0);
+            .compute_list_bind_uniform_set(compute_list, uniforms, 0);
 
         self.device.compute_list_dispatch(
             compute_list,
@@ -216,7 +705,8 @@ impl ConversionContext {
         self.device.sync();
 
         let Channels::YUVA420p { y, u, v, a, .. } = self.channels;
-        let planes = [(y, 1), (u, 2), (v, 2)];
+        let chroma_divisor = if self.chroma_444 { 1 } else { 2 };
+        let planes = [(y, 1), (u, chroma_divisor), (v, chroma_divisor)];
 
         // Copy YUV channels to main frame
         for (plane_idx, (texture, divisor)) in planes.iter().enumerate() {
@@ -230,15 +720,73 @@ impl ConversionContext {
             let buf = alpha_frame.data_mut(0);
             self.copy_plane_data(a, buf, line_size, 1);
 
-            // Fill chroma planes with neutral gray for alpha
-            (1..alpha_frame.planes()).for_each(|plane| {
-                alpha_frame.data_mut(plane).fill(128);
-            });
+            fill_neutral_chroma(alpha_frame);
         }
     }
 }
 
-impl Drop for ConversionContext {
+impl ScalePass {
+    /// Reads `scratch` through `sampler` and writes `width` x `height` RGBA
+    /// floats, the placement is set as a push constant per frame
+    fn new(
+        rd: &mut Gd<RenderingDevice>,
+        scratch: Rid,
+        sampler: Rid,
+        filter: ScaleFilter,
+        linear_input: bool,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, Error> {
+        let mut defines = vec![match filter {
+            ScaleFilter::Nearest => "FILTER_NEAREST",
+            ScaleFilter::Bilinear => "FILTER_BILINEAR",
+            ScaleFilter::Bicubic => "FILTER_BICUBIC",
+            ScaleFilter::Lanczos => "FILTER_LANCZOS",
+        }];
+        if linear_input {
+            defines.push("LINEAR_INPUT");
+        }
+        let shader = compile_shader(rd, include_str!("./glsl/scale.glsl"), &defines)?;
+        let pipeline = rd.compute_pipeline_create(shader);
+
+        let mut fmt = godot::classes::RdTextureFormat::new_gd();
+        fmt.set_format(DataFormat::R32G32B32A32_SFLOAT);
+        fmt.set_usage_bits(TextureUsageBits::STORAGE_BIT | TextureUsageBits::SAMPLING_BIT);
+        fmt.set_width(width);
+        fmt.set_height(height);
+        let scaled = rd.texture_create(fmt, godot::classes::RdTextureView::new_gd());
+
+        let mut input_uni = RdUniform::new_gd();
+        input_uni.set_binding(0);
+        input_uni.set_uniform_type(UniformType::TEXTURE);
+        input_uni.add_id(scratch);
+
+        let mut output_uni = RdUniform::new_gd();
+        output_uni.set_binding(1);
+        output_uni.set_uniform_type(UniformType::IMAGE);
+        output_uni.add_id(scaled);
+
+        let mut sampler_uni = RdUniform::new_gd();
+        sampler_uni.set_binding(2);
+        sampler_uni.set_uniform_type(UniformType::SAMPLER);
+        sampler_uni.add_id(sampler);
+
+        let uniforms = rd.uniform_set_create(
+            Array::from(&[input_uni, output_uni, sampler_uni]),
+            shader,
+            0,
+        );
+
+        Ok(Self {
+            shader,
+            pipeline,
+            uniforms,
+            scaled,
+        })
+    }
+}
+
+impl Drop for DeviceContext {
     fn drop(&mut self) {
         match &mut self.channels {
             Channels::YUVA420p {
@@ -256,9 +804,54 @@ impl Drop for ConversionContext {
             }
         };
 
+        self.device.free_rid(self.scale.scaled);
+        self.device.free_rid(self.scale.uniforms);
+        self.device.free_rid(self.scale.pipeline);
+        self.device.free_rid(self.scale.shader);
+        self.device.free_rid(self.direct_uniforms);
+
         self.device.free_rid(self.uniforms);
         self.device.free_rid(self.pipeline);
         self.device.free_rid(self.sampler);
         self.device.free_rid(self.shader);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ntsc_output_drops_one_frame_in_1001() {
+        let fps = Rational::new(60000, 1001);
+        let mut dropped = 0;
+        for block in 1..=10u64 {
+            for movie_frames in (block - 1) * 1001 + 1..=block * 1001 {
+                if output_frames_due(movie_frames, 60, fps)
+                    == output_frames_due(movie_frames - 1, 60, fps)
+                {
+                    dropped += 1;
+                }
+            }
+            assert_eq!(dropped, block);
+        }
+        assert_eq!(output_frames_due(1001, 60, fps), 1000);
+    }
+
+    #[test]
+    fn matching_rates_write_every_frame() {
+        let fps = Rational::new(60, 1);
+        for movie_frames in 0..1000 {
+            assert_eq!(output_frames_due(movie_frames, 60, fps), movie_frames);
+        }
+    }
+
+    #[test]
+    fn half_rate_output_writes_every_other_frame() {
+        let fps = Rational::new(30, 1);
+        assert_eq!(output_frames_due(1, 60, fps), 1);
+        assert_eq!(output_frames_due(2, 60, fps), 1);
+        assert_eq!(output_frames_due(3, 60, fps), 2);
+        assert_eq!(output_frames_due(600, 60, fps), 300);
+    }
+}
@@ -0,0 +1,537 @@
+//! CPU implementation of `scale.glsl` and `rgb_to_yuv420p.glsl`, used when no
+//! local RenderingDevice can be created. The math mirrors the shaders exactly,
+//! including the unorm rounding the GPU applies when storing to R8/R16 images.
+use ffmpeg_next::util::frame::Video;
+use godot::prelude::*;
+
+use crate::{
+    conversion::fill_neutral_chroma,
+    settings::{ChromaFilter, ColorMatrix, ColorRange, ScaleFilter, Transfer},
+};
+
+/// Source pixels, tightly packed RGBA
+pub enum Pixels<'a> {
+    /// sRGB encoded bytes
+    Rgba8(&'a [u8]),
+    /// Floats, linear from HDR viewports or resampled from either
+    RgbaF(&'a [f32]),
+}
+
+impl Pixels<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Pixels::Rgba8(data) => data.len(),
+            Pixels::RgbaF(data) => data.len(),
+        }
+    }
+
+    #[inline(always)]
+    fn get(&self, index: usize) -> [f32; 4] {
+        let i = index * 4;
+        match self {
+            Pixels::Rgba8(data) => [
+                unorm(data[i]),
+                unorm(data[i + 1]),
+                unorm(data[i + 2]),
+                unorm(data[i + 3]),
+            ],
+            Pixels::RgbaF(data) => [data[i], data[i + 1], data[i + 2], data[i + 3]],
+        }
+    }
+}
+
+/// What the planes are written as
+#[derive(Clone, Copy)]
+pub struct Output {
+    pub ten_bit: bool,
+    /// Full resolution U/V planes instead of half
+    pub chroma_444: bool,
+    pub chroma_filter: ChromaFilter,
+    pub transfer: Transfer,
+    pub matrix: ColorMatrix,
+    pub range: ColorRange,
+}
+
+#[inline(always)]
+fn unorm(byte: u8) -> f32 {
+    byte as f32 / 255.0
+}
+
+#[inline(always)]
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c > 0.04045 {
+        ((c + 0.055) / 1.055).powf(2.4)
+    } else {
+        c / 12.92
+    }
+}
+
+#[inline(always)]
+fn linear_to_srgb(c: f32) -> f32 {
+    let c = c.clamp(0.0, 1.0);
+    if c > 0.0031308 {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    } else {
+        c * 12.92
+    }
+}
+
+/// See `SDR_WHITE_NITS` in the shader
+const SDR_WHITE_NITS: f32 = 203.0;
+/// See `HLG_REFERENCE_WHITE` in the shader
+const HLG_REFERENCE_WHITE: f32 = 0.265;
+
+fn pq_oetf(nits: f32) -> f32 {
+    let y = (nits / 10000.0).clamp(0.0, 1.0).powf(0.159_301_76);
+    ((0.835_937_5 + 18.851_563 * y) / (1.0 + 18.6875 * y)).powf(78.84375)
+}
+
+fn hlg_oetf(e: f32) -> f32 {
+    let e = e.clamp(0.0, 1.0);
+    if e <= 1.0 / 12.0 {
+        (3.0 * e).sqrt()
+    } else {
+        0.178_832_77 * (12.0 * e - 0.284_668_92).ln() + 0.559_910_7
+    }
+}
+
+/// Non-linear R'G'B' in the output primaries and transfer
+#[inline(always)]
+fn encode_rgb([r, g, b, _]: [f32; 4], linear_input: bool, transfer: Transfer) -> [f32; 3] {
+    match transfer {
+        Transfer::Sdr if linear_input => [linear_to_srgb(r), linear_to_srgb(g), linear_to_srgb(b)],
+        Transfer::Sdr => [r, g, b],
+        Transfer::Pq | Transfer::Hlg => {
+            let [r, g, b] = if linear_input {
+                [r, g, b]
+            } else {
+                [srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b)]
+            }
+            .map(|c| c.max(0.0));
+
+            // Rec.709 primaries to Rec.2020
+            let rgb = [
+                0.6274 * r + 0.3293 * g + 0.0433 * b,
+                0.0691 * r + 0.9195 * g + 0.0114 * b,
+                0.0164 * r + 0.0880 * g + 0.8956 * b,
+            ];
+            match transfer {
+                Transfer::Pq => rgb.map(|c| pq_oetf(c * SDR_WHITE_NITS)),
+                _ => rgb.map(|c| hlg_oetf(c * HLG_REFERENCE_WHITE)),
+            }
+        }
+    }
+}
+
+/// Codes normalized to the maximum code of the bit depth
+#[inline(always)]
+fn rgb_to_yuv(pixel: [f32; 4], linear_input: bool, output: &Output) -> [f32; 3] {
+    let [r, g, b] = encode_rgb(pixel, linear_input, output.transfer);
+    let (kr, kb) = output.matrix.coefficients();
+
+    let y = kr * r + (1.0 - kr - kb) * g + kb * b;
+    let u = (b - y) / (2.0 * (1.0 - kb));
+    let v = (r - y) / (2.0 * (1.0 - kr));
+
+    let code_max = if output.ten_bit { 1023.0 } else { 255.0 };
+    match output.range {
+        ColorRange::Limited => {
+            let scale = (code_max + 1.0) / 256.0 / code_max;
+            [
+                (16.0 + 219.0 * y) * scale,
+                (128.0 + 224.0 * u) * scale,
+                (128.0 + 224.0 * v) * scale,
+            ]
+        }
+        ColorRange::Full => {
+            let center = (code_max + 1.0) / 2.0;
+            [
+                y,
+                (u * code_max + center) / code_max,
+                (v * code_max + center) / code_max,
+            ]
+        }
+    }
+}
+
+/// See `SUPPORT` in `scale.glsl`
+fn support(filter: ScaleFilter) -> f32 {
+    match filter {
+        ScaleFilter::Nearest | ScaleFilter::Bilinear => 1.0,
+        ScaleFilter::Bicubic => 2.0,
+        ScaleFilter::Lanczos => 3.0,
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (std::f32::consts::PI * x).sin() / (std::f32::consts::PI * x)
+    }
+}
+
+/// See `kernel` in `scale.glsl`
+fn kernel(filter: ScaleFilter, x: f32) -> f32 {
+    let x = x.abs();
+    if x >= support(filter) {
+        return 0.0;
+    }
+    match filter {
+        ScaleFilter::Nearest | ScaleFilter::Bilinear => 1.0 - x,
+        ScaleFilter::Bicubic if x < 1.0 => (1.5 * x - 2.5) * x * x + 1.0,
+        ScaleFilter::Bicubic => ((-0.5 * x + 2.5) * x - 4.0) * x + 2.0,
+        ScaleFilter::Lanczos => sinc(x) * sinc(x / 3.0),
+    }
+}
+
+/// Where the captured region ends up in the output frame
+#[derive(Clone, Copy)]
+pub struct Placement {
+    /// Part of the movie that is sampled, in movie pixels
+    pub source: Rect2,
+    /// Where it is drawn, in output pixels, the rest is padding
+    pub dest: Rect2,
+    /// Padding in the input's encoding
+    pub pad: [f32; 4],
+}
+
+/// Input pixels and their weights for each output pixel along one axis,
+/// mapping `source` (start, length) in input pixels onto `dest` in output
+/// pixels. Output pixels outside `dest` get no taps. `copy` takes the
+/// nearest pixel whatever the filter
+fn axis_weights(
+    input: usize,
+    source: (f32, f32),
+    dest: (f32, f32),
+    output: usize,
+    filter: ScaleFilter,
+    copy: bool,
+) -> Vec<Vec<(usize, f32)>> {
+    let ratio = source.1 / dest.1;
+    let scale = ratio.max(1.0);
+    (0..output)
+        .map(|o| {
+            let o = o as f32 + 0.5;
+            if o < dest.0 || o >= dest.0 + dest.1 {
+                return Vec::new();
+            }
+
+            let center = source.0 + (o - dest.0) * ratio;
+            if filter == ScaleFilter::Nearest || copy {
+                return vec![((center.floor().max(0.0) as usize).min(input - 1), 1.0)];
+            }
+
+            let lo = (center - support(filter) * scale).floor() as isize;
+            let hi = (center + support(filter) * scale).ceil() as isize;
+            (lo..=hi)
+                .map(|i| {
+                    let weight = kernel(filter, (i as f32 + 0.5 - center) / scale);
+                    (i.clamp(0, input as isize - 1) as usize, weight)
+                })
+                .filter(|(_, weight)| *weight != 0.0)
+                .collect()
+        })
+        .collect()
+}
+
+/// Resamples the `size` pixels as `placement` says into `out_size` RGBA
+/// floats, in the same encoding as the input
+pub fn resample(
+    pixels: &Pixels,
+    size: (u32, u32),
+    placement: &Placement,
+    out_size: (u32, u32),
+    filter: ScaleFilter,
+    linear_input: bool,
+) -> Vec<f32> {
+    let (width, height) = (size.0 as usize, size.1 as usize);
+    let (out_width, out_height) = (out_size.0 as usize, out_size.1 as usize);
+    let Placement { source, dest, pad } = *placement;
+    // A plain crop, copied as is like the shader does
+    let offset = source.position - dest.position;
+    let copy = source.size == dest.size && offset.x.fract() == 0.0 && offset.y.fract() == 0.0;
+    let columns = axis_weights(
+        width,
+        (source.position.x, source.size.x),
+        (dest.position.x, dest.size.x),
+        out_width,
+        filter,
+        copy,
+    );
+    let rows = axis_weights(
+        height,
+        (source.position.y, source.size.y),
+        (dest.position.y, dest.size.y),
+        out_height,
+        filter,
+        copy,
+    );
+
+    // Horizontal pass into out_width x height, then vertical
+    let mut horizontal = vec![0.0; out_width * height * 4];
+    for row in 0..height {
+        for (col, taps) in columns.iter().enumerate() {
+            if taps.is_empty() {
+                continue;
+            }
+            let mut sum = [0.0; 4];
+            for &(x, weight) in taps {
+                for (s, c) in sum.iter_mut().zip(pixels.get(row * width + x)) {
+                    *s += c * weight;
+                }
+            }
+            let total: f32 = taps.iter().map(|(_, weight)| weight).sum();
+            let i = (row * out_width + col) * 4;
+            for (dst, s) in horizontal[i..i + 4].iter_mut().zip(sum) {
+                *dst = s / total;
+            }
+        }
+    }
+
+    let mut scaled = vec![0.0; out_width * out_height * 4];
+    for (row, taps) in rows.iter().enumerate() {
+        let total: f32 = taps.iter().map(|(_, weight)| weight).sum();
+        for (col, column_taps) in columns.iter().enumerate() {
+            let i = (row * out_width + col) * 4;
+            if taps.is_empty() || column_taps.is_empty() {
+                scaled[i..i + 4].copy_from_slice(&pad);
+                continue;
+            }
+
+            let mut sum = [0.0; 4];
+            for &(y, weight) in taps {
+                let i = (y * out_width + col) * 4;
+                for (s, c) in sum.iter_mut().zip(&horizontal[i..i + 4]) {
+                    *s += c * weight;
+                }
+            }
+            // Bicubic and Lanczos overshoot around hard edges
+            let [r, g, b, a] = sum.map(|s| s / total);
+            let rgb = [r, g, b].map(|c| {
+                if linear_input {
+                    c.max(0.0)
+                } else {
+                    c.clamp(0.0, 1.0)
+                }
+            });
+            scaled[i..i + 3].copy_from_slice(&rgb);
+            scaled[i + 3] = a.clamp(0.0, 1.0);
+        }
+    }
+
+    scaled
+}
+
+/// Writes sample `index` of a plane row, as a byte or a 10 bit little
+/// endian code depending on the output
+#[inline(always)]
+fn store(row: &mut [u8], index: usize, value: f32, ten_bit: bool) {
+    if ten_bit {
+        let code = (value.clamp(0.0, 1.0) * 1023.0).round() as u16;
+        row[index * 2..index * 2 + 2].copy_from_slice(&code.to_le_bytes());
+    } else {
+        row[index] = (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+    }
+}
+
+/// Converts a tightly packed RGBA buffer into the Y, U and V planes of
+/// `frame`, and the alpha channel into the luma plane of `alpha_frame`
+pub fn rgba_to_yuv(
+    pixels: Pixels,
+    linear_input: bool,
+    width: u32,
+    height: u32,
+    output: Output,
+    frame: &mut Video,
+    alpha_frame: Option<&mut Video>,
+) {
+    let width = width as usize;
+    let height = height as usize;
+
+    if pixels.len() < width * height * 4 {
+        godot_error!(
+            "CPU conversion expected {} RGBA values, got {}",
+            width * height * 4,
+            pixels.len()
+        );
+        return;
+    }
+
+    // Each pixel is converted once, the chroma filters read the result back
+    // where the shader converts every tap again
+    let yuv: Vec<[f32; 3]> = (0..width * height)
+        .map(|index| rgb_to_yuv(pixels.get(index), linear_input, &output))
+        .collect();
+
+    let y_stride = frame.stride(0);
+    let y_plane = frame.data_mut(0);
+    for row in 0..height {
+        let dst = &mut y_plane[row * y_stride..];
+        for col in 0..width {
+            store(dst, col, yuv[row * width + col][0], output.ten_bit);
+        }
+    }
+
+    // Same edge clamping as the shader
+    let yuv_at = |col: isize, row: isize| {
+        let col = col.clamp(0, width as isize - 1) as usize;
+        let row = row.clamp(0, height as isize - 1) as usize;
+        yuv[row * width + col]
+    };
+    let add = |a: [f32; 3], b: [f32; 3], weight: f32| {
+        [
+            a[0] + b[0] * weight,
+            a[1] + b[1] * weight,
+            a[2] + b[2] * weight,
+        ]
+    };
+
+    let chroma_shift = if output.chroma_444 { 0 } else { 1 };
+    // Rounded up, like FFmpeg sizes the planes of odd frames
+    let chroma_width = (width + chroma_shift) >> chroma_shift;
+    let chroma_height = (height + chroma_shift) >> chroma_shift;
+    for (plane, component) in [(1, 1), (2, 2)] {
+        let stride = frame.stride(plane);
+        let buf = frame.data_mut(plane);
+        for row in 0..chroma_height {
+            let dst = &mut buf[row * stride..];
+            for col in 0..chroma_width {
+                let x = (col << chroma_shift) as isize;
+                let y = (row << chroma_shift) as isize;
+                let yuv = match output.chroma_filter {
+                    _ if output.chroma_444 => yuv_at(x, y),
+                    ChromaFilter::Fast => yuv_at(x, y),
+                    ChromaFilter::Box => {
+                        let mut sum = yuv_at(x, y);
+                        sum = add(sum, yuv_at(x + 1, y), 1.0);
+                        sum = add(sum, yuv_at(x, y + 1), 1.0);
+                        sum = add(sum, yuv_at(x + 1, y + 1), 1.0);
+                        sum.map(|c| c / 4.0)
+                    }
+                    ChromaFilter::Bilinear => {
+                        let mut sum = [0.0; 3];
+                        for dy in 0..2 {
+                            sum = add(sum, yuv_at(x - 1, y + dy), 0.25);
+                            sum = add(sum, yuv_at(x, y + dy), 0.5);
+                            sum = add(sum, yuv_at(x + 1, y + dy), 0.25);
+                        }
+                        sum.map(|c| c * 0.5)
+                    }
+                };
+                store(dst, col, yuv[component], output.ten_bit);
+            }
+        }
+    }
+
+    if let Some(alpha_frame) = alpha_frame {
+        let stride = alpha_frame.stride(0);
+        let buf = alpha_frame.data_mut(0);
+        for row in 0..height {
+            let dst = &mut buf[row * stride..];
+            for col in 0..width {
+                let [_, _, _, a] = pixels.get(row * width + col);
+                store(dst, col, a, output.ten_bit);
+            }
+        }
+
+        fill_neutral_chroma(alpha_frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffmpeg_next::format::Pixel;
+
+    const RED: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+    const GREEN: [f32; 4] = [0.0, 1.0, 0.0, 1.0];
+    const BLUE: [f32; 4] = [0.0, 0.0, 1.0, 1.0];
+    const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+    const BLACK: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+
+    fn output(matrix: ColorMatrix, range: ColorRange, ten_bit: bool) -> Output {
+        Output {
+            ten_bit,
+            chroma_444: false,
+            chroma_filter: ChromaFilter::Bilinear,
+            transfer: Transfer::Sdr,
+            matrix,
+            range,
+        }
+    }
+
+    /// Y, U and V codes the shader stores for one pixel
+    fn codes(pixel: [f32; 4], output: &Output) -> [u16; 3] {
+        let code_max = if output.ten_bit { 1023.0 } else { 255.0 };
+        rgb_to_yuv(pixel, false, output).map(|c| (c.clamp(0.0, 1.0) * code_max).round() as u16)
+    }
+
+    #[test]
+    fn bt709_limited() {
+        let output = output(ColorMatrix::Bt709, ColorRange::Limited, false);
+        assert_eq!(codes(RED, &output), [63, 102, 240]);
+        assert_eq!(codes(GREEN, &output), [173, 42, 26]);
+        assert_eq!(codes(BLUE, &output), [32, 240, 118]);
+        assert_eq!(codes(WHITE, &output), [235, 128, 128]);
+        assert_eq!(codes(BLACK, &output), [16, 128, 128]);
+    }
+
+    #[test]
+    fn bt601_full() {
+        let output = output(ColorMatrix::Bt601, ColorRange::Full, false);
+        assert_eq!(codes(RED, &output), [76, 85, 255]);
+        assert_eq!(codes(BLUE, &output), [29, 255, 107]);
+        assert_eq!(codes(WHITE, &output), [255, 128, 128]);
+        let gray = unorm(128);
+        assert_eq!(codes([gray, gray, gray, 1.0], &output), [128, 128, 128]);
+    }
+
+    #[test]
+    fn ten_bit() {
+        let output_709 = output(ColorMatrix::Bt709, ColorRange::Limited, true);
+        assert_eq!(codes(RED, &output_709), [250, 409, 960]);
+        assert_eq!(codes(WHITE, &output_709), [940, 512, 512]);
+        assert_eq!(codes(BLACK, &output_709), [64, 512, 512]);
+
+        let output_full = output(ColorMatrix::Bt709, ColorRange::Full, true);
+        assert_eq!(codes(GREEN, &output_full), [732, 118, 47]);
+    }
+
+    /// A 2x2 frame with a red left and a black right column
+    fn convert_block(chroma_filter: ChromaFilter) -> Video {
+        let pixels: Vec<f32> = [RED, BLACK, RED, BLACK].concat();
+        let output = Output {
+            chroma_filter,
+            ..output(ColorMatrix::Bt709, ColorRange::Limited, false)
+        };
+        let mut frame = Video::new(Pixel::YUV420P, 2, 2);
+        rgba_to_yuv(
+            Pixels::RgbaF(&pixels),
+            false,
+            2,
+            2,
+            output,
+            &mut frame,
+            None,
+        );
+        frame
+    }
+
+    #[test]
+    fn chroma_filters_match_the_shader() {
+        // Box averages the block: U (102.3 + 128) / 2, V (240 + 128) / 2
+        let frame = convert_block(ChromaFilter::Box);
+        assert_eq!(&frame.data(0)[..2], &[63, 16]);
+        assert_eq!((frame.data(1)[0], frame.data(2)[0]), (115, 184));
+
+        // Bilinear weighs the clamped left neighbor, the pixel and the right
+        // one 1/4, 1/2, 1/4
+        let frame = convert_block(ChromaFilter::Bilinear);
+        assert_eq!((frame.data(1)[0], frame.data(2)[0]), (109, 212));
+
+        // Fast keeps the top left pixel
+        let frame = convert_block(ChromaFilter::Fast);
+        assert_eq!((frame.data(1)[0], frame.data(2)[0]), (102, 240));
+    }
+}
@@ -1,13 +1,134 @@
 #version 450
 
+// ConversionContext inserts #defines for the requested output after #version:
+// TEN_BIT           store 10 bit codes in r16 planes instead of r8
+// LINEAR_INPUT      inputImg holds linear float colors instead of sRGB bytes
+// HDR_PQ, HDR_HLG   BT.2020 primaries with the PQ or HLG transfer
+// CHROMA_444        full resolution U/V planes instead of half
+// CHROMA_BILINEAR   4:2:0 chroma filtered 1/4 1/2 1/4 across, 1/2 1/2 down (left sited)
+// CHROMA_BOX        4:2:0 chroma averaged over the 2x2 block (center sited)
+//                   neither: the top left pixel of the block is used as is
+// KR, KB            luma coefficients of the BT.601, BT.709 or BT.2020 matrix
+// LIMITED_RANGE     16-235 luma and 16-240 chroma (scaled for 10 bit)
+
+#ifdef TEN_BIT
+#define PLANE_FORMAT r16
+const float CODE_MAX = 1023.0;
+#else
+#define PLANE_FORMAT r8
+const float CODE_MAX = 255.0;
+#endif
+
+#if defined(HDR_PQ) || defined(HDR_HLG)
+#define HDR
+#endif
+
 layout(set = 0, binding = 0) uniform texture2D inputImg;
-layout(r8, set = 0, binding = 1) uniform writeonly image2D luma;
-layout(r8, set = 0, binding = 2) uniform writeonly image2D chr_u;
-layout(r8, set = 0, binding = 3) uniform writeonly image2D chr_v;
-layout(r8, set = 0, binding = 4) uniform writeonly image2D alpha;
+layout(PLANE_FORMAT, set = 0, binding = 1) uniform writeonly image2D luma;
+layout(PLANE_FORMAT, set = 0, binding = 2) uniform writeonly image2D chr_u;
+layout(PLANE_FORMAT, set = 0, binding = 3) uniform writeonly image2D chr_v;
+layout(PLANE_FORMAT, set = 0, binding = 4) uniform writeonly image2D alpha;
 
 layout(set = 0, binding = 5) uniform sampler default_sampler;
 
+vec3 srgb_to_linear(vec3 c) {
+    return mix(c / 12.92, pow((c + 0.055) / 1.055, vec3(2.4)), greaterThan(c, vec3(0.04045)));
+}
+
+vec3 linear_to_srgb(vec3 c) {
+    c = clamp(c, 0.0, 1.0);
+    return mix(c * 12.92, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, greaterThan(c, vec3(0.0031308)));
+}
+
+#ifdef HDR
+// Rec.709 primaries to Rec.2020, column major
+const mat3 BT709_TO_BT2020 = mat3(
+    0.6274, 0.0691, 0.0164,
+    0.3293, 0.9195, 0.0880,
+    0.0433, 0.0114, 0.8956);
+
+// Brightness of SDR white (1.0) in nits, see ITU-R BT.2408
+const float SDR_WHITE_NITS = 203.0;
+// Scene light that HLG maps to its 75% reference white
+const float HLG_REFERENCE_WHITE = 0.265;
+
+vec3 pq_oetf(vec3 nits) {
+    vec3 y = pow(clamp(nits / 10000.0, 0.0, 1.0), vec3(0.1593017578125));
+    return pow((0.8359375 + 18.8515625 * y) / (1.0 + 18.6875 * y), vec3(78.84375));
+}
+
+float hlg_oetf(float e) {
+    e = clamp(e, 0.0, 1.0);
+    return e <= 1.0 / 12.0 ? sqrt(3.0 * e) : 0.17883277 * log(12.0 * e - 0.28466892) + 0.55991073;
+}
+#endif
+
+// Non-linear R'G'B' in the output primaries and transfer
+vec3 encode_rgb(vec3 rgb) {
+#ifdef HDR
+#ifndef LINEAR_INPUT
+    rgb = srgb_to_linear(rgb);
+#endif
+    vec3 c = BT709_TO_BT2020 * max(rgb, vec3(0.0));
+#ifdef HDR_PQ
+    return pq_oetf(c * SDR_WHITE_NITS);
+#else
+    return vec3(hlg_oetf(c.r * HLG_REFERENCE_WHITE), hlg_oetf(c.g * HLG_REFERENCE_WHITE), hlg_oetf(c.b * HLG_REFERENCE_WHITE));
+#endif
+#elif defined(LINEAR_INPUT)
+    return linear_to_srgb(rgb);
+#else
+    return rgb;
+#endif
+}
+
+// Returns codes normalized to CODE_MAX
+vec3 rgb_to_yuv(vec3 rgb) {
+    vec3 c = encode_rgb(rgb);
+    float y = KR * c.r + (1.0 - KR - KB) * c.g + KB * c.b;
+    float u = (c.b - y) / (2.0 * (1.0 - KB));
+    float v = (c.r - y) / (2.0 * (1.0 - KR));
+
+#ifdef LIMITED_RANGE
+    return vec3(16.0 + 219.0 * y, 128.0 + 224.0 * u, 128.0 + 224.0 * v) * ((CODE_MAX + 1.0) / 256.0) / CODE_MAX;
+#else
+    return vec3(y * CODE_MAX, u * CODE_MAX + (CODE_MAX + 1.0) / 2.0, v * CODE_MAX + (CODE_MAX + 1.0) / 2.0) / CODE_MAX;
+#endif
+}
+
+float plane_value(float v) {
+#ifdef TEN_BIT
+    // r16 stores the 10 bit code as is, which is what YUV420P10LE expects
+    return round(clamp(v, 0.0, 1.0) * 1023.0) / 65535.0;
+#else
+    return v;
+#endif
+}
+
+// YUV of a pixel, clamped to the edges of the image
+vec3 yuv_at(ivec2 p, ivec2 size) {
+    p = clamp(p, ivec2(0), size - 1);
+    return rgb_to_yuv(texelFetch(sampler2D(inputImg, default_sampler), p, 0).rgb);
+}
+
+// Chroma of the 2x2 block whose top left pixel is p
+vec2 block_chroma(ivec2 p, ivec2 size, vec3 yuv) {
+#if defined(CHROMA_BOX)
+    vec3 sum = yuv + yuv_at(p + ivec2(1, 0), size) + yuv_at(p + ivec2(0, 1), size) + yuv_at(p + ivec2(1, 1), size);
+    return sum.yz / 4.0;
+#elif defined(CHROMA_BILINEAR)
+    vec3 sum = vec3(0.0);
+    for (int dy = 0; dy < 2; dy++) {
+        sum += yuv_at(p + ivec2(-1, dy), size) * 0.25;
+        sum += yuv_at(p + ivec2(0, dy), size) * 0.5;
+        sum += yuv_at(p + ivec2(1, dy), size) * 0.25;
+    }
+    return sum.yz * 0.5;
+#else
+    return yuv.yz;
+#endif
+}
+
 layout(local_size_x = 16, local_size_y = 16) in;
 void main() {
     ivec2 pixel_coords = ivec2(gl_GlobalInvocationID.xy);
@@ -19,19 +140,20 @@ void main() {
 
     vec4 rgba = texelFetch(sampler2D(inputImg, default_sampler), pixel_coords, 0);
 
-    vec4 yuva = vec4(0.0);
-
-    yuva.x = rgba.r * 0.299 + rgba.g * 0.587 + rgba.b * 0.114;
-    yuva.y = rgba.r * -0.169 + rgba.g * -0.331 + rgba.b * 0.5 + 0.5;
-    yuva.z = rgba.r * 0.5 + rgba.g * -0.419 + rgba.b * -0.081 + 0.5;
-    yuva.w = rgba.a;
+    vec4 yuva = vec4(rgb_to_yuv(rgba.rgb), rgba.a);
 
-    imageStore(luma, pixel_coords, vec4(yuva.x));
-    imageStore(alpha, pixel_coords, vec4(yuva.w));
+    imageStore(luma, pixel_coords, vec4(plane_value(yuva.x)));
+    imageStore(alpha, pixel_coords, vec4(plane_value(yuva.w)));
 
+#ifdef CHROMA_444
+    imageStore(chr_u, pixel_coords, vec4(plane_value(yuva.y)));
+    imageStore(chr_v, pixel_coords, vec4(plane_value(yuva.z)));
+#else
     if (pixel_coords.x % 2 == 0 && pixel_coords.y % 2 == 0) {
         ivec2 chroma_coords = pixel_coords / 2;
-        imageStore(chr_u, chroma_coords, vec4(yuva.y));
-        imageStore(chr_v, chroma_coords, vec4(yuva.z));
+        vec2 chroma = block_chroma(pixel_coords, image_size, yuva.xyz);
+        imageStore(chr_u, chroma_coords, vec4(plane_value(chroma.x)));
+        imageStore(chr_v, chroma_coords, vec4(plane_value(chroma.y)));
     }
+#endif
 }
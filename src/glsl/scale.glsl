@@ -0,0 +1,115 @@
+#version 450
+
+// Resamples the captured region of the frame to the output size before
+// rgb_to_yuv420p.glsl converts it. Values stay in the input's encoding, sRGB or linear.
+// FILTER_NEAREST, FILTER_BILINEAR, FILTER_BICUBIC or FILTER_LANCZOS selects the kernel
+// LINEAR_INPUT      inputImg holds linear float colors, which may exceed 1.0
+
+layout(set = 0, binding = 0) uniform texture2D inputImg;
+layout(rgba32f, set = 0, binding = 1) uniform writeonly image2D outputImg;
+
+layout(set = 0, binding = 2) uniform sampler default_sampler;
+
+layout(push_constant, std430) uniform Params {
+    // x, y, width and height of the captured region in input pixels, fractional while easing
+    vec4 source_rect;
+    // Where the region is drawn in output pixels, the rest is padding
+    vec4 dest_rect;
+    // In the input's encoding
+    vec4 pad_color;
+} params;
+
+const float PI = 3.14159265358979;
+
+#if defined(FILTER_BILINEAR)
+const float SUPPORT = 1.0;
+#elif defined(FILTER_BICUBIC)
+const float SUPPORT = 2.0;
+#else
+const float SUPPORT = 3.0;
+#endif
+
+float sinc(float x) {
+    return x == 0.0 ? 1.0 : sin(PI * x) / (PI * x);
+}
+
+// Filter weight at distance x, in output pixels
+float kernel(float x) {
+    x = abs(x);
+    if (x >= SUPPORT) {
+        return 0.0;
+    }
+#if defined(FILTER_BILINEAR)
+    return 1.0 - x;
+#elif defined(FILTER_BICUBIC)
+    // Catmull-Rom
+    return x < 1.0 ? (1.5 * x - 2.5) * x * x + 1.0 : ((-0.5 * x + 2.5) * x - 4.0) * x + 2.0;
+#else
+    // Lanczos3
+    return sinc(x) * sinc(x / 3.0);
+#endif
+}
+
+vec4 fetch(ivec2 p, ivec2 size) {
+    return texelFetch(sampler2D(inputImg, default_sampler), clamp(p, ivec2(0), size - 1), 0);
+}
+
+layout(local_size_x = 16, local_size_y = 16) in;
+void main() {
+    ivec2 pixel_coords = ivec2(gl_GlobalInvocationID.xy);
+    ivec2 out_size = imageSize(outputImg);
+
+    if (pixel_coords.x >= out_size.x || pixel_coords.y >= out_size.y) {
+        return;
+    }
+
+    vec2 out_center = vec2(pixel_coords) + 0.5;
+    if (any(lessThan(out_center, params.dest_rect.xy)) || any(greaterThanEqual(out_center, params.dest_rect.xy + params.dest_rect.zw))) {
+        imageStore(outputImg, pixel_coords, params.pad_color);
+        return;
+    }
+
+    ivec2 in_size = textureSize(sampler2D(inputImg, default_sampler), 0);
+    vec2 ratio = params.source_rect.zw / params.dest_rect.zw;
+    // Pixel center in input coordinates
+    vec2 center = params.source_rect.xy + (out_center - params.dest_rect.xy) * ratio;
+
+    if (params.source_rect.zw == params.dest_rect.zw && fract(params.source_rect.xy - params.dest_rect.xy) == vec2(0.0)) {
+        // A plain crop, copied as is
+        imageStore(outputImg, pixel_coords, fetch(ivec2(floor(center)), in_size));
+        return;
+    }
+
+#ifdef FILTER_NEAREST
+    vec4 color = fetch(ivec2(floor(center)), in_size);
+#else
+    // Widen the kernel when shrinking so every input pixel contributes
+    vec2 scale = max(ratio, vec2(1.0));
+    ivec2 lo = ivec2(floor(center - SUPPORT * scale));
+    ivec2 hi = ivec2(ceil(center + SUPPORT * scale));
+
+    vec4 sum = vec4(0.0);
+    float total = 0.0;
+    for (int y = lo.y; y <= hi.y; y++) {
+        float wy = kernel((float(y) + 0.5 - center.y) / scale.y);
+        if (wy == 0.0) {
+            continue;
+        }
+        for (int x = lo.x; x <= hi.x; x++) {
+            float w = wy * kernel((float(x) + 0.5 - center.x) / scale.x);
+            sum += fetch(ivec2(x, y), in_size) * w;
+            total += w;
+        }
+    }
+    vec4 color = sum / total;
+#endif
+
+    // Bicubic and Lanczos overshoot around hard edges
+#ifdef LINEAR_INPUT
+    color = vec4(max(color.rgb, vec3(0.0)), clamp(color.a, 0.0, 1.0));
+#else
+    color = clamp(color, 0.0, 1.0);
+#endif
+
+    imageStore(outputImg, pixel_coords, color);
+}
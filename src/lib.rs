@@ -1,7 +1,14 @@
-use std::{ffi::c_void, mem::size_of, path::PathBuf};
+use std::{
+    collections::VecDeque,
+    ffi::{c_void, CString},
+    mem::size_of,
+    path::PathBuf,
+    sync::mpsc,
+    time::Duration,
+};
 
 use ffmpeg::encoder::Video;
-use ffmpeg_next::{self as ffmpeg, encoder};
+use ffmpeg_next as ffmpeg;
 use godot::{
     engine::{audio_server::SpeakerMode, Engine, IMovieWriter, MovieWriter},
     global::Error as GodotError,
@@ -9,12 +16,28 @@ use godot::{
 };
 
 mod audio;
+mod capture;
+mod container;
 mod conversion;
+mod cpu_conversion;
+mod log;
+mod options;
+mod preset;
+mod scene_cut;
 mod settings;
+mod two_pass;
 
-use audio::OpusEncoder;
+use audio::{AudioClock, AudioEncoder};
+use capture::CaptureRect;
+use container::Container;
 use conversion::ConversionContext;
-use settings::EncoderConfig;
+use log::{log_error, log_print, log_warn};
+use preset::SorkinPreset;
+use scene_cut::SceneCutDetector;
+use settings::{
+    ColorMatrix, ColorRange, EncoderConfig, RateControl, ResizePolicy, Transfer, VideoCodec,
+};
+use two_pass::{FirstPass, Intermediate, IntermediateFile, Pass, PassStats, Progress, Replayed};
 
 #[derive(Debug)]
 pub enum Error {
@@ -30,22 +53,73 @@ impl From<ffmpeg::Error> for Error {
     }
 }
 
+/// `movie.webm` -> `movie_2.webm` for the files started after a resize
+fn segment_path(path: &str, segment: u32) -> String {
+    let path = PathBuf::from(path);
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+    let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("webm");
+    path.with_file_name(format!("{stem}_{segment}.{ext}"))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// `movie.webm` -> `movie_alpha.webm`, keeping whatever extension was given
+fn sidecar_path(path: &str) -> String {
+    let path = PathBuf::from(path);
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+    let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("webm");
+    path.with_file_name(format!("{stem}_alpha.{ext}"))
+        .to_string_lossy()
+        .into_owned()
+}
+
 #[derive(GodotClass)]
 #[class(base=MovieWriter)]
 pub struct SorkinWriter {
     paused: bool,
     base: Base<MovieWriter>,
-    encoder: Option<VP9Encoder>,
-    alpha_encoder: Option<VP9Encoder>,
+    encoder: Option<OutputEncoder>,
+    alpha_encoder: Option<OutputEncoder>,
     conversion_context: Option<ConversionContext>,
+    /// Lossless frame store used instead of `encoder` in two-pass mode
+    intermediate: Option<Intermediate>,
+    /// Movie frames received from Godot
     frame_count: usize,
-    fps: u32,
+    /// Frames written to the output, differs from `frame_count` when the
+    /// output rate isn't Movie Maker's
+    output_frame_count: usize,
+    /// Output frame rate
+    fps: ffmpeg::Rational,
+    /// Movie Maker's fixed fps, the rate `write_frame` is called at
+    movie_fps: u32,
+    /// File currently written, differs from `movie_path` after a resize
+    /// started a new file
     output_path: Option<String>,
+    movie_path: String,
+    /// Number of the current file, counting from 1
+    segment: u32,
+    /// `output_frame_count` when the current file was started
+    segment_start_frame: usize,
     config: EncoderConfig,
+    /// Set by `set_preset`, replaces `config` when the next recording starts
+    pending_config: Option<EncoderConfig>,
     total_frame_time: f64,
     recording_start_time: Option<std::time::Instant>,
     audio_buffer: Vec<f32>,
-    audio_samples_per_video_frame: usize,
+    /// Splits the mix into per frame blocks, `None` without audio
+    audio_clock: Option<AudioClock>,
+    /// Layout Godot mixes the audio in, fixed when recording starts
+    speaker_mode: SpeakerMode,
+    /// Set by `request_keyframe`, cleared once the next frame is written
+    keyframe_requested: bool,
+    scene_cut: Option<SceneCutDetector>,
+    capture: CaptureRect,
 }
 
 #[godot_api]
@@ -60,6 +134,426 @@ impl SorkinWriter {
     fn is_paused(&self) -> bool {
         self.paused
     }
+
+    /// Use `preset` for the next recording instead of the project settings'
+    /// preset, null goes back to the project settings
+    #[func]
+    fn set_preset(&mut self, preset: Option<Gd<SorkinPreset>>) {
+        let mut config = EncoderConfig::from_project_settings();
+        if let Some(preset) = preset {
+            preset.bind().apply(&mut config);
+        }
+
+        if self.conversion_context.is_some()
+            || self.encoder.is_some()
+            || self.intermediate.is_some()
+        {
+            godot_warn!("Sorkin is recording, the preset applies from the next recording on");
+        }
+        self.pending_config = Some(config);
+    }
+
+    /// Make the next recorded frame a keyframe, e.g. on a hard cut
+    #[func]
+    fn request_keyframe(&mut self) {
+        self.keyframe_requested = true;
+    }
+
+    /// Record only `rect` of the movie from the next frame on, scaled to the
+    /// output size. Changes are eased over `capture_smoothing` seconds, an
+    /// empty rect goes back to the whole movie
+    #[func]
+    fn set_capture_rect(&mut self, rect: Rect2i) {
+        self.capture.set_target(rect);
+    }
+}
+
+/// Flushes the buffered audio and finishes both output files
+fn finish_encoders(
+    mut encoder: OutputEncoder,
+    alpha_encoder: Option<OutputEncoder>,
+    audio_buffer: &mut Vec<f32>,
+) -> Result<(), Error> {
+    if let Some(audio_encoder) = encoder.audio_encoder.as_ref() {
+        if !audio_buffer.is_empty() {
+            let opus_frame_size_total = audio_encoder.samples_per_frame();
+
+            if audio_buffer.len() < opus_frame_size_total {
+                audio_buffer.resize(opus_frame_size_total, 0.0);
+            }
+
+            while audio_buffer.len() >= opus_frame_size_total {
+                let opus_frame_data: Vec<f32> =
+                    audio_buffer.drain(0..opus_frame_size_total).collect();
+                let audio_block_size = opus_frame_data.len() * size_of::<f32>();
+
+                if let Err(e) = encoder
+                    .write_audio_data(opus_frame_data.as_ptr() as *const c_void, audio_block_size)
+                {
+                    log_error!("Failed to write final audio data: {:?}", e);
+                }
+            }
+        }
+    }
+
+    if let Some(alpha_encoder) = alpha_encoder {
+        match alpha_encoder.finish() {
+            Ok(_) => {
+                log_print!("Alpha encoder finished successfully");
+            }
+            Err(e) => {
+                log_error!("Failed to finish alpha encoder: {:?}", e);
+            }
+        }
+    }
+
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Size of everything written to disk, including a sidecar matte
+fn output_size_bytes(path: &str, config: &EncoderConfig) -> u64 {
+    let mut paths = vec![path.to_string()];
+    if config.alpha_channel && config.alpha_sidecar {
+        paths.push(sidecar_path(path));
+    }
+
+    paths
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// How often the main thread checks whether the offline passes are done
+const OFFLINE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The offline passes of two-pass and target size mode, run on a worker
+/// thread that only logs through `log`
+struct OfflineEncode {
+    path: String,
+    config: EncoderConfig,
+    fps: ffmpeg::Rational,
+    speaker_mode: SpeakerMode,
+    audio_buffer: Vec<f32>,
+}
+
+impl OfflineEncode {
+    /// Encodes the lossless intermediate into the output file. Two-pass mode
+    /// gathers first pass statistics before the real encode, target size mode
+    /// picks the bitrate from the recorded duration and re-encodes while the
+    /// output is still too big.
+    fn run(mut self, intermediate: Intermediate) -> Result<(), Error> {
+        let path = self.path.clone();
+        let (width, height) = (intermediate.width, intermediate.height);
+        let fps = self.fps;
+        let file = intermediate.finish()?;
+
+        let mut config = match Container::from_path(&path) {
+            Some(container) => container.resolve(&self.config),
+            None => self.config.clone(),
+        };
+        let duration = file.frame_count as f64 / f64::from(fps);
+        let fit_size = config.target_size_mb > 0.0;
+        if fit_size {
+            two_pass::fit_to_size(&mut config, duration, file.audio_channels);
+            log_print!(
+                "Fitting {:.2}s into {} MB, video bitrate {} kbps",
+                duration,
+                config.target_size_mb,
+                config.bitrate_kbps
+            );
+        }
+
+        // only libvpx exposes its first pass statistics in memory
+        let stats = if config.video_codec == VideoCodec::Vp9 {
+            let mut first_pass = FirstPass::new(width, height, fps, &config)?;
+            let mut progress = Progress::new("pass 1/2", file.frame_count);
+            file.replay(|item| {
+                if let Replayed::Video(frame, alpha_frame) = item {
+                    first_pass.write_frame(&frame, alpha_frame.as_ref())?;
+                    progress.step();
+                }
+                Ok(())
+            })?;
+            Some(first_pass.finish()?)
+        } else {
+            None
+        };
+
+        let label = if stats.is_some() {
+            "pass 2/2"
+        } else {
+            "encoding"
+        };
+        for attempt in 1..=two_pass::TARGET_SIZE_ATTEMPTS {
+            self.audio_buffer.clear();
+            let (encoder, alpha_encoder) =
+                self.encode_replay(&file, &path, &config, stats.as_ref(), label)?;
+            finish_encoders(encoder, alpha_encoder, &mut self.audio_buffer)?;
+
+            if !fit_size {
+                break;
+            }
+
+            let size = output_size_bytes(&path, &config);
+            let target = config.target_size_mb * two_pass::BYTES_PER_MB;
+            if size as f64 <= target {
+                break;
+            }
+
+            if attempt == two_pass::TARGET_SIZE_ATTEMPTS
+                || !two_pass::shrink_to_size(&mut config, duration, size)
+            {
+                log_warn!(
+                    "Output is {:.2} MB, still above the {} MB target",
+                    size as f64 / two_pass::BYTES_PER_MB,
+                    config.target_size_mb
+                );
+                break;
+            }
+
+            log_print!(
+                "Output is {:.2} MB, re-encoding at {} kbps to fit {} MB",
+                size as f64 / two_pass::BYTES_PER_MB,
+                config.bitrate_kbps,
+                config.target_size_mb
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Replays the intermediate through freshly created output encoders
+    fn encode_replay(
+        &mut self,
+        file: &IntermediateFile,
+        path: &str,
+        config: &EncoderConfig,
+        stats: Option<&PassStats>,
+        label: &'static str,
+    ) -> Result<(OutputEncoder, Option<OutputEncoder>), Error> {
+        let (width, height) = (file.width, file.height);
+        let fps = self.fps;
+
+        let mut alpha_encoder = if config.alpha_channel && config.alpha_sidecar {
+            let alpha_stats = stats
+                .and_then(|s| s.alpha.clone())
+                .map(|video| PassStats { video, alpha: None });
+            Some(OutputEncoder::new(
+                sidecar_path(path),
+                width,
+                height,
+                fps,
+                self.speaker_mode,
+                &config.matte_config(),
+                alpha_stats.as_ref(),
+            )?)
+        } else {
+            None
+        };
+        let mut encoder = OutputEncoder::new(
+            path.to_string(),
+            width,
+            height,
+            fps,
+            self.speaker_mode,
+            config,
+            stats,
+        )?;
+
+        let mut progress = Progress::new(label, file.frame_count);
+        let audio_buffer = &mut self.audio_buffer;
+        file.replay(|item| {
+            match item {
+                Replayed::Video(frame, mut alpha_frame) => {
+                    if let Some(ref mut alpha_encoder) = alpha_encoder {
+                        if let Some(alpha_frame) = alpha_frame.take() {
+                            alpha_encoder.write_frame(&alpha_frame, None)?;
+                        }
+                    }
+
+                    encoder.write_frame(&frame, alpha_frame.as_ref())?;
+                    progress.step();
+                }
+                Replayed::Audio(samples) => {
+                    audio_buffer.extend_from_slice(&samples);
+                    write_buffered_audio(&mut encoder, audio_buffer);
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok((encoder, alpha_encoder))
+    }
+}
+
+impl SorkinWriter {
+    /// Config as the encoders see it for the current output file
+    fn resolved_config(&self) -> EncoderConfig {
+        self.output_path
+            .as_deref()
+            .and_then(Container::from_path)
+            .map_or_else(|| self.config.clone(), |c| c.resolve(&self.config))
+    }
+
+    /// Finishes whatever output is open, encoding the intermediate first in
+    /// two-pass and target size mode
+    fn finish_output(&mut self) -> Result<(), Error> {
+        if let Some(intermediate) = self.intermediate.take() {
+            self.encode_offline(intermediate)
+        } else if let Some(encoder) = self.encoder.take() {
+            let alpha_encoder = self.alpha_encoder.take();
+            finish_encoders(encoder, alpha_encoder, &mut self.audio_buffer)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Runs the offline passes on a worker thread and prints what it logs
+    /// until it is done. This can run during shutdown, so nothing but the
+    /// log is touched while waiting.
+    fn encode_offline(&mut self, intermediate: Intermediate) -> Result<(), Error> {
+        let path = self
+            .output_path
+            .clone()
+            .ok_or_else(|| Error::Encoding("No output path".to_string()))?;
+        let job = OfflineEncode {
+            path,
+            config: self.config.clone(),
+            fps: self.fps,
+            speaker_mode: self.speaker_mode,
+            audio_buffer: std::mem::take(&mut self.audio_buffer),
+        };
+        let (queue, messages) = mpsc::channel();
+
+        let worker = std::thread::Builder::new()
+            .name("sorkin-encode".to_string())
+            .spawn(move || {
+                log::redirect(queue);
+                job.run(intermediate)
+            })
+            .map_err(|e| Error::Encoding(format!("Failed to start the encoding thread: {e}")))?;
+
+        while !worker.is_finished() {
+            if let Ok((level, message)) = messages.recv_timeout(OFFLINE_POLL_INTERVAL) {
+                log::emit(level, &message);
+            }
+        }
+        let result = worker
+            .join()
+            .map_err(|_| Error::Encoding("Encoding thread panicked".to_string()));
+        for (level, message) in messages.try_iter() {
+            log::emit(level, &message);
+        }
+
+        result?
+    }
+
+    /// Deals with the movie size changing to `size` as `resize_policy` says
+    fn handle_resize(
+        &mut self,
+        size: Vector2i,
+        format: godot::classes::image::Format,
+    ) -> Result<(), Error> {
+        let Some(previous) = self.conversion_context.take() else {
+            return Ok(());
+        };
+        let (old_width, old_height) = previous.source_size();
+        let (width, height) = (size.x as u32, size.y as u32);
+
+        match self.config.resize_policy {
+            ResizePolicy::Rescale => {
+                godot_warn!(
+                    "Movie size changed from {old_width}x{old_height} to {width}x{height}, fitting it into the {}x{} output (resize_policy: Rescale)",
+                    previous.width,
+                    previous.height
+                );
+                let output = (previous.width, previous.height);
+                drop(previous);
+                self.conversion_context = Some(ConversionContext::new(
+                    format,
+                    &self.resolved_config(),
+                    width,
+                    height,
+                    output,
+                )?);
+            }
+            ResizePolicy::NewFile => {
+                drop(previous);
+                self.finish_output()?;
+                self.segment += 1;
+                let path = segment_path(&self.movie_path, self.segment);
+                godot_warn!(
+                    "Movie size changed from {old_width}x{old_height} to {width}x{height}, continuing in {path} (resize_policy: New File)"
+                );
+                self.output_path = Some(path);
+                self.segment_start_frame = self.output_frame_count;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Size of everything written to disk, including a sidecar matte
+    fn output_size_bytes(&self) -> u64 {
+        self.output_path
+            .as_deref()
+            .map_or(0, |path| output_size_bytes(path, &self.config))
+    }
+}
+
+/// Config the next recording starts with, a pending preset wins over the
+/// current one
+fn upcoming_config<'a>(
+    pending: &'a Option<EncoderConfig>,
+    config: &'a EncoderConfig,
+) -> &'a EncoderConfig {
+    pending.as_ref().unwrap_or(config)
+}
+
+/// Converts Godot's 32 bit integer samples to the floats the encoders take
+///
+/// # Safety
+/// `block` must point to at least `len` samples
+unsafe fn audio_block_to_f32(block: *const c_void, len: usize) -> Vec<f32> {
+    let as_i32_samples = unsafe { std::slice::from_raw_parts(block as *const i32, len) };
+
+    as_i32_samples
+        .iter()
+        .map(|&sample| {
+            // without this we get crazy clipping on edge cases
+            if sample == i32::MIN {
+                -1.0f32
+            } else {
+                sample as f32 / i32::MAX as f32
+            }
+        })
+        .collect()
+}
+
+/// Feeds every whole codec frame in `audio_buffer` to the encoder and keeps
+/// the remainder buffered for the next video frame
+fn write_buffered_audio(encoder: &mut OutputEncoder, audio_buffer: &mut Vec<f32>) {
+    let Some(opus_frame_size_total) = encoder
+        .audio_encoder
+        .as_ref()
+        .map(|a| a.samples_per_frame())
+    else {
+        return;
+    };
+
+    while audio_buffer.len() >= opus_frame_size_total {
+        let opus_frame_data: Vec<f32> = audio_buffer.drain(0..opus_frame_size_total).collect();
+
+        let audio_block_size = opus_frame_data.len() * size_of::<f32>();
+
+        if let Err(e) =
+            encoder.write_audio_data(opus_frame_data.as_ptr() as *const c_void, audio_block_size)
+        {
+            log_error!("Failed to write audio data: {:?}", e);
+        }
+    }
 }
 
 #[godot_api]
@@ -70,39 +564,54 @@ impl IMovieWriter for SorkinWriter {
             encoder: None,
             alpha_encoder: None,
             conversion_context: None,
+            intermediate: None,
             frame_count: 0,
-            fps: 30,
+            output_frame_count: 0,
+            fps: ffmpeg::Rational::new(30, 1),
+            movie_fps: 30,
             output_path: None,
+            movie_path: String::new(),
+            segment: 1,
+            segment_start_frame: 0,
             config: EncoderConfig::from_project_settings(),
+            pending_config: None,
             total_frame_time: 0.0,
             recording_start_time: None,
             audio_buffer: Vec::new(),
-            audio_samples_per_video_frame: 0,
+            audio_clock: None,
+            speaker_mode: SpeakerMode::STEREO,
             paused: false,
+            keyframe_requested: false,
+            scene_cut: None,
+            capture: CaptureRect::new(None, 0.0),
         }
     }
 
     fn handles_file(&self, path: GString) -> bool {
-        let path: PathBuf = path.to_string().into();
-        let ext = path.extension().and_then(|s| s.to_str());
-        if let Some("webm") = ext {
-            godot_print!("using Sorkin writer for extension {:?}", ext.unwrap());
-            true
-        } else {
-            false
+        match Container::from_path(&path.to_string()) {
+            Some(container) if container.is_available() => {
+                godot_print!("using Sorkin writer for container {:?}", container);
+                true
+            }
+            Some(container) => {
+                godot_print!(
+                    "Sorkin cannot write {:?}, no H.264 encoder in this FFmpeg build",
+                    container
+                );
+                false
+            }
+            None => false,
         }
     }
 
+    // Godot asks for the audio format before `write_begin` applies a preset
+    // set with `set_preset`, so both answer for the config about to be used
     fn get_audio_mix_rate(&self) -> u32 {
-        if self.config.enable_audio {
-            audio::OPUS_SAMPLE_RATE
-        } else {
-            0
-        }
+        audio::mix_rate(upcoming_config(&self.pending_config, &self.config))
     }
 
     fn get_audio_speaker_mode(&self) -> SpeakerMode {
-        SpeakerMode::STEREO
+        audio::speaker_mode(upcoming_config(&self.pending_config, &self.config).speakers)
     }
 
     fn write_begin(&mut self, movie_size: Vector2i, fps: u32, path: GString) -> GodotError {
@@ -119,22 +628,52 @@ impl IMovieWriter for SorkinWriter {
         ffmpeg::init().unwrap();
         godot_print!("FFmpeg initialized successfully");
 
-        self.fps = fps;
+        if let Some(config) = self.pending_config.take() {
+            self.config = config;
+        }
+
+        let container = Container::from_path(&path.to_string());
+        if let Some(Err(e)) = container.map(|c| c.check_config(&self.config)) {
+            godot_error!("Invalid Sorkin configuration: {:?}", e);
+            return GodotError::ERR_INVALID_PARAMETER;
+        }
+
+        self.movie_fps = fps;
+        self.fps = self
+            .config
+            .frame_rate
+            .unwrap_or(ffmpeg::Rational::new(fps as i32, 1));
+        if self.config.frame_rate.is_some() {
+            godot_print!("Encoding at {} fps", self.fps);
+        }
         self.frame_count = 0;
+        self.output_frame_count = 0;
         self.output_path = Some(path.to_string());
+        self.movie_path = path.to_string();
+        self.segment = 1;
+        self.segment_start_frame = 0;
         godot_print!("Output path set to: {:?}", self.output_path);
         self.total_frame_time = 0.0;
         self.recording_start_time = Some(std::time::Instant::now());
 
+        // Godot mixes audio per movie frame, so blocks follow its rate and
+        // not the output's
         let audio_mix_rate = self.get_audio_mix_rate();
+        self.audio_clock = (audio_mix_rate > 0).then(|| AudioClock::new(audio_mix_rate, fps));
+        self.speaker_mode = self.get_audio_speaker_mode();
         if audio_mix_rate > 0 {
-            self.audio_samples_per_video_frame =
-                (audio_mix_rate / fps) as usize * audio::STEREO_CHANNELS as usize;
-        } else {
-            self.audio_samples_per_video_frame = 0;
+            godot_print!(
+                "Recording {} audio channels",
+                audio::channel_count(self.speaker_mode)
+            );
         }
         self.audio_buffer.clear();
 
+        self.keyframe_requested = false;
+        self.scene_cut = (self.config.scene_cut_threshold > 0.0)
+            .then(|| SceneCutDetector::new(self.config.scene_cut_threshold));
+        self.capture = CaptureRect::new(self.config.capture_rect, self.config.capture_smoothing);
+
         godot_print!("write_begin completed successfully");
         GodotError::OK
     }
@@ -150,23 +689,66 @@ impl IMovieWriter for SorkinWriter {
         let frame_start = std::time::Instant::now();
         let size = frame_image.get_size();
 
-        if self.encoder.is_none() {
-            if let Some(ref path) = self.output_path {
-                let width = size.x as u32;
-                let height = size.y as u32;
+        let resized = self
+            .conversion_context
+            .as_ref()
+            .is_some_and(|context| context.source_size() != (size.x as u32, size.y as u32));
+        if resized {
+            if let Err(e) = self.handle_resize(size, frame_image.get_format()) {
+                godot_error!("Failed to handle the movie size change: {:?}", e);
+                return GodotError::ERR_CANT_CREATE;
+            }
+        }
 
-                let alpha_encoder = if self.config.alpha_channel {
-                    let alpha_path = path.replace(".webm", "_alpha.webm");
-                    match VP9Encoder::new(
-                        alpha_path,
+        if self.encoder.is_none() && self.intermediate.is_none() {
+            let Some(ref path) = self.output_path else {
+                return GodotError::ERR_UNCONFIGURED;
+            };
+
+            let width = size.x as u32;
+            let height = size.y as u32;
+
+            let config = self.resolved_config();
+
+            // The region recorded at the start decides the output size
+            let region = self.capture.initial_size(size);
+
+            godot_print!("Creating ConversionContext...");
+            let conversion_context = match ConversionContext::new(
+                frame_image.get_format(),
+                &config,
+                width,
+                height,
+                config.output_size(region.x as u32, region.y as u32),
+            ) {
+                Ok(conversion_context) => conversion_context,
+                Err(e) => {
+                    godot_error!("ConversionContext::new failed: {:?}", e);
+                    return GodotError::ERR_CANT_CREATE;
+                }
+            };
+            // Encoders take the output size, which may differ from the movie size
+            let (width, height) = (conversion_context.width, conversion_context.height);
+
+            if self.config.uses_intermediate() {
+                godot_print!("Storing frames losslessly, they are encoded when recording ends");
+                match Intermediate::new(width, height, self.fps, self.speaker_mode, &config) {
+                    Ok(intermediate) => self.intermediate = Some(intermediate),
+                    Err(e) => {
+                        godot_error!("Failed to create two-pass intermediate: {:?}", e);
+                        return GodotError::ERR_CANT_CREATE;
+                    }
+                }
+            } else {
+                let alpha_encoder = if self.config.alpha_channel && self.config.alpha_sidecar {
+                    match OutputEncoder::new(
+                        sidecar_path(path),
                         width,
                         height,
-                        self.fps as f64,
-                        &EncoderConfig {
-                            enable_audio: false,
-                            alpha_channel: false,
-                            ..self.config.clone()
-                        },
+                        self.fps,
+                        self.speaker_mode,
+                        &config.matte_config(),
+                        None,
                     ) {
                         Ok(encoder) => Some(encoder),
                         Err(e) => {
@@ -178,58 +760,59 @@ impl IMovieWriter for SorkinWriter {
                     None
                 };
 
-                let pixel_format = if self.config.alpha_channel {
-                    ffmpeg::format::Pixel::YUVA420P
-                } else {
-                    ffmpeg::format::Pixel::YUV420P
-                };
-
-                godot_print!("Creating VP9Encoder...");
-                let encoder_result =
-                    VP9Encoder::new(path.clone(), width, height, self.fps as f64, &self.config);
-                if let Err(ref e) = encoder_result {
-                    godot_error!("VP9Encoder::new failed: {:?}", e);
-                }
-
-                godot_print!("Creating ConversionContext...");
-                let conversion_result = ConversionContext::new(
-                    godot::classes::image::Format::RGBA8,
-                    pixel_format,
+                godot_print!("Creating OutputEncoder...");
+                match OutputEncoder::new(
+                    path.clone(),
                     width,
                     height,
-                );
-                if let Err(ref e) = conversion_result {
-                    godot_error!("ConversionContext::new failed: {:?}", e);
-                }
-
-                match (encoder_result, conversion_result) {
-                    (Ok(encoder), Ok(conversion_context)) => {
+                    self.fps,
+                    self.speaker_mode,
+                    &config,
+                    None,
+                ) {
+                    Ok(encoder) => {
                         self.encoder = Some(encoder);
                         self.alpha_encoder = alpha_encoder;
-                        self.conversion_context = Some(conversion_context);
                     }
-                    (Err(e), _) | (_, Err(e)) => {
-                        godot_error!("Failed to initialize encoder: {:?}", e);
+                    Err(e) => {
+                        godot_error!("OutputEncoder::new failed: {:?}", e);
                         return GodotError::ERR_CANT_CREATE;
                     }
                 }
-            } else {
-                return GodotError::ERR_UNCONFIGURED;
             }
+
+            self.conversion_context = Some(conversion_context);
         }
 
-        if let (Some(encoder), Some(conversion_context)) =
-            (&mut self.encoder, &mut self.conversion_context)
-        {
+        let time_base = match (&self.encoder, &self.intermediate) {
+            (Some(encoder), _) => encoder.encoder.time_base(),
+            (_, Some(intermediate)) => intermediate.time_base(),
+            _ => return GodotError::ERR_UNCONFIGURED,
+        };
+
+        let region = self.capture.next(size, self.movie_fps as f64);
+
+        // Output frames starting during this movie frame, more than one or
+        // none when the output rate differs from Movie Maker's
+        let due =
+            conversion::output_frames_due(self.frame_count as u64 + 1, self.movie_fps, self.fps)
+                as usize;
+        let repeats = due.saturating_sub(self.output_frame_count);
+
+        if repeats > 0 {
+            let Some(conversion_context) = self.conversion_context.as_mut() else {
+                return GodotError::ERR_UNCONFIGURED;
+            };
+
             let mut frame = ffmpeg::frame::Video::new(
-                ffmpeg::format::Pixel::YUV420P,
+                conversion_context.format,
                 conversion_context.width,
                 conversion_context.height,
             );
 
-            let mut alpha_frame = if self.alpha_encoder.is_some() {
+            let mut alpha_frame = if self.config.alpha_channel {
                 Some(ffmpeg::frame::Video::new(
-                    ffmpeg::format::Pixel::YUV420P,
+                    conversion_context.format,
                     conversion_context.width,
                     conversion_context.height,
                 ))
@@ -237,216 +820,440 @@ impl IMovieWriter for SorkinWriter {
                 None
             };
 
-            conversion_context.convert(frame_image, &mut frame, alpha_frame.as_mut());
+            conversion_context.convert(frame_image, region, &mut frame, alpha_frame.as_mut());
 
-            let pts = conversion::frame_to_pts(
-                self.frame_count as i64,
-                self.fps as i64,
-                encoder.encoder.time_base().1 as i64,
-            );
-            frame.set_pts(Some(pts));
-
-            // Handle alpha encoder if present
-            if let (Some(ref mut alpha_encoder), Some(mut alpha_frame)) =
-                (&mut self.alpha_encoder, alpha_frame)
-            {
-                alpha_frame.set_pts(Some(pts));
-                if let Err(e) = alpha_encoder.write_frame(&alpha_frame) {
-                    godot_error!("Failed to write alpha frame: {:?}", e);
+            let scene_cut = self
+                .scene_cut
+                .as_mut()
+                .is_some_and(|detector| detector.is_cut(&frame));
+            if std::mem::take(&mut self.keyframe_requested) || scene_cut {
+                frame.set_kind(ffmpeg::picture::Type::I);
+                if let Some(ref mut alpha_frame) = alpha_frame {
+                    alpha_frame.set_kind(ffmpeg::picture::Type::I);
                 }
             }
 
-            match encoder.write_frame(&frame) {
-                Ok(_) => {
-                    if !audio_frame_block.is_null() && self.config.enable_audio {
-                        let as_i32_samples = unsafe {
-                            std::slice::from_raw_parts(
-                                audio_frame_block as *const i32,
-                                self.audio_samples_per_video_frame,
-                            )
-                        };
-
-                        let audio_data: Vec<f32> = as_i32_samples
-                            .iter()
-                            .map(|&sample| {
-                                // without this we get crazy clipping on edge cases
-                                if sample == i32::MIN {
-                                    -1.0f32
-                                } else {
-                                    sample as f32 / i32::MAX as f32
-                                }
-                            })
-                            .collect();
-
-                        self.audio_buffer.extend_from_slice(&audio_data);
-
-                        let opus_frame_size_total =
-                            audio::OPUS_FRAME_SIZE * audio::STEREO_CHANNELS as usize;
-
-                        while self.audio_buffer.len() >= opus_frame_size_total {
-                            let opus_frame_data: Vec<f32> =
-                                self.audio_buffer.drain(0..opus_frame_size_total).collect();
-
-                            let audio_block_size = opus_frame_data.len() * size_of::<f32>();
-
-                            if let Err(e) = encoder.write_audio_data(
-                                opus_frame_data.as_ptr() as *const c_void,
-                                audio_block_size,
-                            ) {
-                                godot_error!("Failed to write audio data: {:?}", e);
-                            }
+            // Legacy sidecar mode writes the matte to its own file
+            let mut sidecar_alpha_frame = if self.alpha_encoder.is_some() {
+                alpha_frame.take()
+            } else {
+                None
+            };
+
+            for _ in 0..repeats {
+                let pts = conversion::frame_to_pts(
+                    (self.output_frame_count - self.segment_start_frame) as i64,
+                    self.fps,
+                    time_base,
+                );
+                for frame in [
+                    Some(&mut frame),
+                    alpha_frame.as_mut(),
+                    sidecar_alpha_frame.as_mut(),
+                ]
+                .into_iter()
+                .flatten()
+                {
+                    frame.set_pts(Some(pts));
+                }
+
+                if let Some(ref mut intermediate) = self.intermediate {
+                    if let Err(e) = intermediate.write_frame(&frame, alpha_frame.as_ref()) {
+                        godot_error!("Failed to write two-pass intermediate frame: {:?}", e);
+                        return GodotError::ERR_FILE_CANT_WRITE;
+                    }
+                } else if let Some(ref mut encoder) = self.encoder {
+                    if let (Some(alpha_encoder), Some(sidecar_alpha_frame)) =
+                        (self.alpha_encoder.as_mut(), sidecar_alpha_frame.as_ref())
+                    {
+                        if let Err(e) = alpha_encoder.write_frame(sidecar_alpha_frame, None) {
+                            godot_error!("Failed to write alpha frame: {:?}", e);
                         }
                     }
 
-                    self.frame_count += 1;
-                    let frame_time = frame_start.elapsed();
-                    self.total_frame_time += frame_time.as_secs_f64();
-                    GodotError::OK
+                    if encoder.write_frame(&frame, alpha_frame.as_ref()).is_err() {
+                        return GodotError::ERR_FILE_CANT_WRITE;
+                    }
+                }
+                self.output_frame_count += 1;
+
+                // Repeats of a keyframe don't need to be keyframes themselves
+                for frame in [
+                    Some(&mut frame),
+                    alpha_frame.as_mut(),
+                    sidecar_alpha_frame.as_mut(),
+                ]
+                .into_iter()
+                .flatten()
+                {
+                    frame.set_kind(ffmpeg::picture::Type::None);
                 }
-                Err(_) => GodotError::ERR_FILE_CANT_WRITE,
             }
-        } else {
-            GodotError::ERR_UNCONFIGURED
         }
+
+        let audio_data = match self.audio_clock.as_mut() {
+            Some(clock) if !audio_frame_block.is_null() && self.config.enable_audio => {
+                let channels = audio::channel_count(self.speaker_mode) as usize;
+                let block =
+                    unsafe { audio_block_to_f32(audio_frame_block, clock.block_len() * channels) };
+                audio::fit_to_len(block, clock.next_frame(), channels)
+            }
+            _ => Vec::new(),
+        };
+
+        if let Some(ref mut intermediate) = self.intermediate {
+            if let Err(e) = intermediate.write_audio(&audio_data) {
+                godot_error!("Failed to write audio data: {:?}", e);
+            }
+        } else if let Some(ref mut encoder) = self.encoder {
+            self.audio_buffer.extend_from_slice(&audio_data);
+            write_buffered_audio(encoder, &mut self.audio_buffer);
+        }
+
+        self.frame_count += 1;
+        let frame_time = frame_start.elapsed();
+        self.total_frame_time += frame_time.as_secs_f64();
+        GodotError::OK
     }
 
     fn write_end(&mut self) {
-        if let Some(mut encoder) = self.encoder.take() {
-            if self.config.enable_audio && !self.audio_buffer.is_empty() {
-                if let Some(audio_encoder) = encoder.audio_encoder.as_ref() {
-                    let opus_frame_size_total = audio_encoder.encoder.frame_size() as usize
-                        * audio_encoder.encoder.channels() as usize;
-
-                    if self.audio_buffer.len() < opus_frame_size_total {
-                        self.audio_buffer.resize(opus_frame_size_total, 0.0);
-                    }
+        self.conversion_context.take();
 
-                    while self.audio_buffer.len() >= opus_frame_size_total {
-                        let opus_frame_data: Vec<f32> =
-                            self.audio_buffer.drain(0..opus_frame_size_total).collect();
-                        let audio_block_size = opus_frame_data.len() * size_of::<f32>();
+        if self.encoder.is_none() && self.intermediate.is_none() {
+            return;
+        }
 
-                        if let Err(e) = encoder.write_audio_data(
-                            opus_frame_data.as_ptr() as *const c_void,
-                            audio_block_size,
-                        ) {
-                            godot_error!("Failed to write final audio data: {:?}", e);
-                        }
-                    }
-                }
-            }
+        match self.finish_output() {
+            Ok(()) => {
+                let average_frame_time = if self.frame_count > 0 {
+                    self.total_frame_time / self.frame_count as f64
+                } else {
+                    0.0
+                };
 
-            if let Some(alpha_encoder) = self.alpha_encoder.take() {
-                match alpha_encoder.finish() {
-                    Ok(_) => {
-                        godot_print!("Alpha encoder finished successfully");
-                    }
-                    Err(e) => {
-                        godot_error!("Failed to finish alpha encoder: {:?}", e);
-                    }
-                }
+                let total_recording_time = self
+                    .recording_start_time
+                    .map(|start| start.elapsed().as_secs_f64())
+                    .unwrap_or(0.0);
+
+                godot_print!(
+                    "Video encoding completed successfully. Total frames: {}, Average frame time: {:.2}ms, Total recording time: {:.2}s, Output size: {:.2} MB",
+                    self.output_frame_count,
+                    average_frame_time * 1000.0,
+                    total_recording_time,
+                    self.output_size_bytes() as f64 / two_pass::BYTES_PER_MB
+                );
             }
-
-            self.conversion_context.take();
-            match encoder.finish() {
-                Ok(_) => {
-                    let average_frame_time = if self.frame_count > 0 {
-                        self.total_frame_time / self.frame_count as f64
-                    } else {
-                        0.0
-                    };
-
-                    let total_recording_time = self
-                        .recording_start_time
-                        .map(|start| start.elapsed().as_secs_f64())
-                        .unwrap_or(0.0);
-
-                    godot_print!(
-                        "Video encoding completed successfully. Total frames: {}, Average frame time: {:.2}ms, Total recording time: {:.2}s",
-                        self.frame_count,
-                        average_frame_time * 1000.0,
-                        total_recording_time
-                    );
-                }
-                Err(e) => {
-                    godot_error!("Failed to finish encoding: {:?}", e);
-                }
+            Err(e) => {
+                godot_error!("Failed to finish encoding: {:?}", e);
             }
         }
     }
 }
 
-struct VP9Encoder {
+/// Matroska BlockAddID used for the VP9 alpha plane, see the WebM `AlphaMode` element
+const ALPHA_BLOCK_ADD_ID: u64 = 1;
+
+/// One output file: the video encoder, the optional matte and audio
+/// encoders and the muxer they write to
+struct OutputEncoder {
     output_context: ffmpeg::format::context::Output,
     video_stream_index: usize,
     audio_stream_index: Option<usize>,
     encoder: Video,
-    audio_encoder: Option<OpusEncoder>,
+    audio_encoder: Option<AudioEncoder>,
+    /// Encodes the matte carried as BlockAdditional data on each video block
+    alpha_encoder: Option<Video>,
+    /// Pixel format with an alpha plane the matte is merged into, for FFV1
+    native_alpha: Option<ffmpeg::format::Pixel>,
+    pending_video: VecDeque<ffmpeg::packet::Packet>,
+    pending_alpha: VecDeque<ffmpeg::packet::Packet>,
 }
 
-impl VP9Encoder {
+impl OutputEncoder {
     fn configure_encoder(
         codec: ffmpeg_next::Codec,
         width: u32,
         height: u32,
-        fps: f64,
+        fps: ffmpeg::Rational,
         global_header: bool,
         config: &EncoderConfig,
+        pass: Pass,
     ) -> Result<Video, Error> {
         let mut encoder = ffmpeg_next::codec::context::Context::new_with_codec(codec)
             .encoder()
             .video()
             .map_err(|e| Error::Encoding(format!("Could not create encoder context: {e}")))?;
 
+        let (width, height) = coded_size(codec, width, height);
         encoder.set_width(width);
         encoder.set_height(height);
-        encoder.set_format(ffmpeg::format::Pixel::YUV420P);
-        encoder.set_time_base((1, (fps as i32) * 1000));
-        encoder.set_frame_rate(Some((fps as i32, 1)));
+        // libvpx picks profile 1, 2 or 3 by itself from the pixel format
+        encoder.set_format(config.encoder_pixel_format());
+
+        // Must describe exactly what ConversionContext produces
+        encoder.set_colorspace(match config.matrix() {
+            ColorMatrix::Bt601 => ffmpeg::color::Space::SMPTE170M,
+            ColorMatrix::Bt709 => ffmpeg::color::Space::BT709,
+            ColorMatrix::Bt2020 => ffmpeg::color::Space::BT2020NCL,
+        });
+        encoder.set_color_range(match config.color_range {
+            ColorRange::Limited => ffmpeg::color::Range::MPEG,
+            ColorRange::Full => ffmpeg::color::Range::JPEG,
+        });
+        // Godot renders with sRGB primaries and transfer unless HDR converts them
+        let (primaries, transfer) = match config.transfer {
+            Transfer::Sdr => (
+                ffmpeg::color::Primaries::BT709,
+                ffmpeg::color::TransferCharacteristic::IEC61966_2_1,
+            ),
+            Transfer::Pq => (
+                ffmpeg::color::Primaries::BT2020,
+                ffmpeg::color::TransferCharacteristic::SMPTE2084,
+            ),
+            Transfer::Hlg => (
+                ffmpeg::color::Primaries::BT2020,
+                ffmpeg::color::TransferCharacteristic::ARIB_STD_B67,
+            ),
+        };
+        unsafe {
+            let context = encoder.as_mut_ptr();
+            (*context).color_primaries = primaries.into();
+            (*context).color_trc = transfer.into();
+            (*context).chroma_sample_location = config.chroma_location().into();
+        }
+        encoder.set_time_base(conversion::video_time_base(fps));
+        encoder.set_frame_rate(Some(fps));
 
+        let mut flags = ffmpeg::codec::Flags::empty();
         if global_header {
-            encoder.set_flags(ffmpeg::codec::Flags::GLOBAL_HEADER);
+            flags |= ffmpeg::codec::Flags::GLOBAL_HEADER;
+        }
+
+        let stats_in =
+            match pass {
+                Pass::Single => None,
+                Pass::First => {
+                    flags |= ffmpeg::codec::Flags::PASS1;
+                    None
+                }
+                Pass::Second(stats) => {
+                    flags |= ffmpeg::codec::Flags::PASS2;
+                    Some(CString::new(stats).map_err(|e| {
+                        Error::Encoding(format!("Invalid first pass statistics: {e}"))
+                    })?)
+                }
+            };
+
+        if !flags.is_empty() {
+            encoder.set_flags(flags);
+        }
+
+        if let Some(ref stats_in) = stats_in {
+            unsafe {
+                (*encoder.as_mut_ptr()).stats_in = stats_in.as_ptr() as *mut _;
+            }
         }
 
         let mut dict = ffmpeg::Dictionary::new();
-        dict.set("cpu-used", "5");
-        dict.set("auto-alt-ref", "0");
-        dict.set("lag-in-frames", "0");
-        dict.set("row-mt", "1");
-        dict.set("speed", "5");
 
         // Apply encoder config settings
         let thread_count_str = config.thread_count.to_string();
         dict.set("threads", &thread_count_str);
 
-        let quality_str = match config.quality {
-            settings::Quality::Realtime => "realtime",
-            settings::Quality::Good => "good",
-            settings::Quality::Best => "best",
-        };
-        dict.set("quality", quality_str);
-        dict.set("deadline", quality_str);
+        // Fixed when recording alpha, see `EncoderConfig::with_aligned_keyframes`
+        let (keyframe_interval, min_keyframe_interval) =
+            config.keyframe_interval_frames(f64::from(fps));
+        if keyframe_interval > 0 {
+            dict.set("g", &keyframe_interval.to_string());
+        }
+        if min_keyframe_interval > 0 {
+            dict.set("keyint_min", &min_keyframe_interval.to_string());
+        }
+
+        match config.video_codec {
+            VideoCodec::Vp9 => {
+                // look-ahead and alt-ref frames are what make a second pass worthwhile
+                let two_pass = !matches!(pass, Pass::Single);
+
+                dict.set("cpu-used", "5");
+                dict.set("auto-alt-ref", if two_pass { "1" } else { "0" });
+                dict.set("lag-in-frames", if two_pass { "25" } else { "0" });
+                dict.set("row-mt", "1");
+                dict.set("speed", "5");
+
+                let quality_str = match config.quality {
+                    settings::Quality::Realtime if two_pass => "good",
+                    settings::Quality::Realtime => "realtime",
+                    settings::Quality::Good => "good",
+                    settings::Quality::Best => "best",
+                };
+                dict.set("quality", quality_str);
+                dict.set("deadline", quality_str);
 
-        encoder
-            .open_as_with(ffmpeg::codec::Id::VP9, dict)
-            .map_err(|e| Error::Encoding(format!("Failed to open encoder: {e}")))
+                if config.pixel_art() {
+                    // screen content tools keep flat colors and hard edges intact
+                    dict.set("tune-content", "screen");
+                }
+            }
+            VideoCodec::Ffv1 => {
+                // version 3 is required for slice threading
+                dict.set("level", "3");
+                dict.set("slicecrc", "1");
+
+                let (coder, context) = match config.quality {
+                    settings::Quality::Realtime => ("0", "0"),
+                    settings::Quality::Good => ("1", "0"),
+                    settings::Quality::Best => ("1", "1"),
+                };
+                dict.set("coder", coder);
+                dict.set("context", context);
+            }
+            VideoCodec::H264 => {
+                let preset = match config.quality {
+                    settings::Quality::Realtime => "veryfast",
+                    settings::Quality::Good => "medium",
+                    settings::Quality::Best => "slow",
+                };
+
+                if codec.name() == "libx264" {
+                    dict.set("preset", preset);
+                    dict.set("profile", "high");
+                    // requested keyframes should be seekable, not just I-frames
+                    dict.set("forced-idr", "1");
+                } else if codec.name() == "libopenh264" {
+                    dict.set("profile", "high");
+                    dict.set("rc_mode", "quality");
+                }
+            }
+            #[cfg(feature = "av1")]
+            VideoCodec::Av1 => {
+                if codec.name() == "libsvtav1" {
+                    // SVT-AV1 presets run from 0 (slowest) to 13 (fastest)
+                    let preset = match config.quality {
+                        settings::Quality::Realtime => "10",
+                        settings::Quality::Good => "6",
+                        settings::Quality::Best => "3",
+                    };
+                    dict.set("preset", preset);
+                } else {
+                    let (usage, cpu_used) = match config.quality {
+                        settings::Quality::Realtime => ("realtime", "8"),
+                        settings::Quality::Good => ("good", "4"),
+                        settings::Quality::Best => ("good", "1"),
+                    };
+                    dict.set("usage", usage);
+                    dict.set("cpu-used", cpu_used);
+                    dict.set("row-mt", "1");
+                }
+            }
+        }
+
+        if config.video_codec != VideoCodec::Ffv1 {
+            Self::set_rate_control(&mut dict, codec, config);
+        }
+
+        unsafe {
+            options::merge(
+                &mut dict,
+                encoder.as_mut_ptr() as *mut c_void,
+                &config.video_options,
+                codec.name(),
+            );
+        }
+
+        let mut encoder = encoder
+            .open_as_with(codec, dict)
+            .map_err(|e| Error::Encoding(format!("Failed to open encoder: {e}")))?;
+
+        // libvpx decodes the statistics while opening, don't keep a dangling pointer
+        if stats_in.is_some() {
+            unsafe {
+                (*encoder.as_mut_ptr()).stats_in = std::ptr::null_mut();
+            }
+        }
+
+        Ok(encoder)
+    }
+
+    /// Maps the rate control settings onto the generic AVCodecContext options,
+    /// which libvpx, libaom, SVT-AV1 and x264 each interpret slightly differently
+    fn set_rate_control(
+        dict: &mut ffmpeg::Dictionary,
+        codec: ffmpeg::Codec,
+        config: &EncoderConfig,
+    ) {
+        let bits = |kbps: u32| (kbps as u64 * 1000).to_string();
+        let bitrate = bits(config.bitrate_kbps);
+        // one second of VBV buffer
+        let bufsize = bits(config.bitrate_kbps);
+        let crf = config.crf().to_string();
+        // openh264 has no CRF, only quality and bitrate modes
+        let has_crf = codec.name() != "libopenh264";
+
+        match config.rate_control {
+            RateControl::ConstantQuality => {
+                if has_crf {
+                    dict.set("crf", &crf);
+                    // libvpx and libaom only switch to pure constant quality without a bitrate
+                    dict.set("b", "0");
+                }
+            }
+            RateControl::ConstrainedQuality => {
+                if has_crf {
+                    dict.set("crf", &crf);
+                }
+                // SVT-AV1 treats any bitrate as a VBR target, the others use it as a ceiling
+                if codec.name() != "libsvtav1" {
+                    dict.set("b", &bitrate);
+                }
+                dict.set("maxrate", &bitrate);
+                dict.set("bufsize", &bufsize);
+            }
+            RateControl::Vbr => {
+                dict.set("b", &bitrate);
+                if config.min_bitrate_kbps > 0 {
+                    dict.set("minrate", &bits(config.min_bitrate_kbps));
+                }
+                if config.max_bitrate_kbps > 0 {
+                    dict.set("maxrate", &bits(config.max_bitrate_kbps));
+                    dict.set("bufsize", &bits(config.max_bitrate_kbps));
+                }
+            }
+            RateControl::Cbr => {
+                // min == max == target is how every wrapped encoder detects CBR
+                dict.set("b", &bitrate);
+                dict.set("minrate", &bitrate);
+                dict.set("maxrate", &bitrate);
+                dict.set("bufsize", &bufsize);
+                if codec.name() == "libx264" {
+                    dict.set("nal-hrd", "cbr");
+                }
+            }
+        }
+
+        if !has_crf && config.rate_control != RateControl::ConstantQuality {
+            dict.set("rc_mode", "bitrate");
+        }
     }
 
     fn new(
         path: String,
         width: u32,
         height: u32,
-        fps: f64,
+        fps: ffmpeg::Rational,
+        speaker_mode: SpeakerMode,
         config: &EncoderConfig,
+        stats: Option<&PassStats>,
     ) -> Result<Self, Error> {
-        godot_print!("VP9Encoder::new - Opening output file: {}", path);
+        log_print!("OutputEncoder::new - Opening output file: {}", path);
         let mut output_context = match ffmpeg::format::output(&path) {
             Ok(ctx) => {
-                godot_print!("Output context created successfully");
+                log_print!("Output context created successfully");
                 ctx
             }
             Err(e) => {
-                godot_error!("Failed to create output context for '{}': {:?}", path, e);
+                log_error!("Failed to create output context for '{}': {:?}", path, e);
                 return Err(e.into());
             }
         };
@@ -456,55 +1263,151 @@ impl VP9Encoder {
             .flags()
             .contains(ffmpeg::format::Flags::GLOBAL_HEADER);
 
-        let codec = encoder::find(ffmpeg::codec::Id::VP9)
-            .ok_or_else(|| Error::Encoding("Codec not found".to_string()))?;
+        let container = Container::from_path(&path);
+        let config = &container.map_or_else(|| config.clone(), |c| c.resolve(config));
+
+        let codec = config.video_codec.find_encoder().ok_or_else(|| {
+            Error::Encoding(format!(
+                "No {:?} encoder in this FFmpeg build",
+                config.video_codec
+            ))
+        })?;
+
+        log_print!("Using Codec {:?}", codec.name());
+        if coded_size(codec, width, height) != (width, height) {
+            log_warn!(
+                "{} only encodes even sizes, the {}x{} window is padded by repeating its last column or row",
+                codec.name(),
+                width,
+                height
+            );
+        }
+
+        // FFV1 keeps the matte in its own pixel format, VP9 in BlockAdditional data
+        let native_alpha = config.native_alpha();
+        let embed_alpha = config.alpha_channel && !config.alpha_sidecar && !native_alpha;
 
-        godot_print!("Using Codec {:?}", codec.name());
+        let pass = stats.map_or(Pass::Single, |s| Pass::Second(&s.video));
+        let alpha_pass = stats
+            .and_then(|s| s.alpha.as_deref())
+            .map_or(Pass::Single, Pass::Second);
 
         let video_stream_index = {
-            let mut video_stream =
-                output_context.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::VP9))?;
+            let mut video_stream = output_context.add_stream(codec)?;
 
             let encoder =
-                Self::configure_encoder(codec, width, height, fps, global_header, config)?;
-            video_stream.set_time_base((1, (fps as i32) * 1000));
+                Self::configure_encoder(codec, width, height, fps, global_header, config, pass)?;
+            video_stream.set_time_base(conversion::video_time_base(fps));
             video_stream.set_parameters(&encoder);
+
+            #[cfg(not(target_os = "windows"))]
+            {
+                let coded = coded_size(codec, width, height);
+                if coded != (width, height) {
+                    attach_frame_cropping(&mut video_stream, coded, (width, height));
+                }
+            }
+
+            if embed_alpha {
+                let mut metadata = ffmpeg::Dictionary::new();
+                metadata.set("alpha_mode", "1");
+                video_stream.set_metadata(metadata);
+            }
+
             video_stream.index()
         };
 
-        let (audio_stream_index, audio_encoder) = if path.ends_with(".webm") && config.enable_audio
-        {
-            let opus_encoder = OpusEncoder::new(
-                audio::OPUS_SAMPLE_RATE,
-                godot::engine::audio_server::SpeakerMode::STEREO,
-                config,
-            )
-            .map_err(|e| Error::Encoding(format!("Failed to create Opus encoder: {e:?}")))?;
-
-            let mut audio_stream = output_context.add_stream(opus_encoder.codec)?;
-            audio_stream.set_time_base(opus_encoder.time_base());
-            audio_stream.set_parameters(&opus_encoder.encoder);
-
-            (Some(audio_stream.index()), Some(opus_encoder))
+        let (audio_stream_index, audio_encoder) = if config.enable_audio {
+            let audio_encoder = AudioEncoder::new(audio::OPUS_SAMPLE_RATE, speaker_mode, config)
+                .map_err(|e| Error::Encoding(format!("Failed to create audio encoder: {e:?}")))?;
+
+            let mut audio_stream = output_context.add_stream(audio_encoder.codec)?;
+            audio_stream.set_time_base(audio_encoder.time_base());
+            audio_stream.set_parameters(&audio_encoder.encoder);
+
+            (Some(audio_stream.index()), Some(audio_encoder))
         } else {
             (None, None)
         };
 
-        let encoder = Self::configure_encoder(codec, width, height, fps, global_header, config)?;
+        let encoder =
+            Self::configure_encoder(codec, width, height, fps, global_header, config, pass)?;
+
+        // The matte gets its own encoder with the same settings, so both
+        // streams emit exactly one packet per frame in the same order
+        let alpha_encoder = if embed_alpha {
+            Some(Self::configure_encoder(
+                codec,
+                width,
+                height,
+                fps,
+                global_header,
+                &config.matte_config(),
+                alpha_pass,
+            )?)
+        } else {
+            None
+        };
 
-        output_context.write_header()?;
+        let mut muxer_options = ffmpeg::Dictionary::new();
+        if container.is_some_and(Container::is_mp4_family) {
+            // Move the moov atom to the front so the file streams before it is fully downloaded
+            muxer_options.set("movflags", "+faststart");
+        }
+        let muxer_name = output_context.format().name().to_string();
+        unsafe {
+            options::merge(
+                &mut muxer_options,
+                output_context.as_mut_ptr() as *mut c_void,
+                &config.muxer_options,
+                &muxer_name,
+            );
+        }
+        options::report_unused(
+            &output_context.write_header_with(muxer_options)?,
+            &muxer_name,
+        );
 
-        Ok(VP9Encoder {
+        Ok(OutputEncoder {
             output_context,
             video_stream_index,
             audio_stream_index,
             encoder,
             audio_encoder,
+            alpha_encoder,
+            native_alpha: native_alpha.then(|| config.encoder_pixel_format()),
+            pending_video: VecDeque::new(),
+            pending_alpha: VecDeque::new(),
         })
     }
 
-    fn write_frame(&mut self, frame: &ffmpeg::frame::Video) -> Result<(), ffmpeg::Error> {
-        self.encoder.send_frame(frame)?;
+    fn write_frame(
+        &mut self,
+        frame: &ffmpeg::frame::Video,
+        alpha_frame: Option<&ffmpeg::frame::Video>,
+    ) -> Result<(), ffmpeg::Error> {
+        let merged;
+        let frame = match (self.native_alpha, alpha_frame) {
+            (Some(format), Some(alpha_frame)) => {
+                merged = conversion::merge_alpha(frame, alpha_frame, format);
+                &merged
+            }
+            _ => frame,
+        };
+
+        let (width, height) = (self.encoder.width(), self.encoder.height());
+        if (frame.width(), frame.height()) != (width, height) {
+            self.encoder
+                .send_frame(&conversion::pad_frame(frame, width, height))?;
+        } else {
+            self.encoder.send_frame(frame)?;
+        }
+
+        if let (Some(alpha_encoder), Some(alpha_frame)) = (self.alpha_encoder.as_mut(), alpha_frame)
+        {
+            alpha_encoder.send_frame(alpha_frame)?;
+        }
+
         self.receive_and_write_video_packets()?;
 
         Ok(())
@@ -540,8 +1443,16 @@ impl VP9Encoder {
 
     fn finish(mut self) -> Result<(), ffmpeg::Error> {
         self.encoder.send_eof()?;
+        if let Some(alpha_encoder) = self.alpha_encoder.as_mut() {
+            alpha_encoder.send_eof()?;
+        }
         self.receive_and_write_video_packets()?;
 
+        // Anything left unpaired is written without a matte rather than dropped
+        while let Some(packet) = self.pending_video.pop_front() {
+            self.write_video_packet(packet)?;
+        }
+
         if let (Some(ref mut audio_encoder), Some(audio_stream_index)) =
             (self.audio_encoder.as_mut(), self.audio_stream_index)
         {
@@ -560,7 +1471,7 @@ impl VP9Encoder {
                     }
                 }
                 Err(e) => {
-                    godot_error!("Failed to finish audio encoder: {:?}", e);
+                    log_error!("Failed to finish audio encoder: {:?}", e);
                 }
             }
         }
@@ -573,19 +1484,137 @@ impl VP9Encoder {
         let mut packet = ffmpeg::packet::Packet::empty();
 
         while self.encoder.receive_packet(&mut packet).is_ok() {
-            packet.set_stream(self.video_stream_index);
-            packet.rescale_ts(
-                self.encoder.time_base(),
-                self.output_context
-                    .stream(self.video_stream_index)
-                    .unwrap()
-                    .time_base(),
-            );
-            packet.write_interleaved(&mut self.output_context)?;
+            if self.alpha_encoder.is_none() {
+                self.write_video_packet(packet)?;
+            } else {
+                self.pending_video.push_back(packet);
+            }
+            packet = ffmpeg::packet::Packet::empty();
+        }
+
+        let Some(alpha_encoder) = self.alpha_encoder.as_mut() else {
+            return Ok(());
+        };
+
+        while alpha_encoder.receive_packet(&mut packet).is_ok() {
+            self.pending_alpha.push_back(packet);
+            packet = ffmpeg::packet::Packet::empty();
+        }
+
+        while !self.pending_video.is_empty() && !self.pending_alpha.is_empty() {
+            let mut video_packet = self.pending_video.pop_front().unwrap();
+            let alpha_packet = self.pending_alpha.pop_front().unwrap();
+
+            if video_packet.pts() == alpha_packet.pts() {
+                attach_alpha_block_additional(&mut video_packet, &alpha_packet);
+            } else {
+                log_error!(
+                    "Alpha packet pts {:?} does not match video pts {:?}, dropping matte",
+                    alpha_packet.pts(),
+                    video_packet.pts()
+                );
+            }
+
+            self.write_video_packet(video_packet)?;
         }
 
         Ok(())
     }
+
+    fn write_video_packet(
+        &mut self,
+        mut packet: ffmpeg::packet::Packet,
+    ) -> Result<(), ffmpeg::Error> {
+        packet.set_stream(self.video_stream_index);
+        packet.rescale_ts(
+            self.encoder.time_base(),
+            self.output_context
+                .stream(self.video_stream_index)
+                .unwrap()
+                .time_base(),
+        );
+        packet.write_interleaved(&mut self.output_context)
+    }
+}
+
+/// Frame size the encoder is opened with. 4:2:0 H.264 can't code odd sizes
+/// at all and SVT-AV1 refuses them, everything else keeps the window size
+fn coded_size(codec: ffmpeg::Codec, width: u32, height: u32) -> (u32, u32) {
+    match codec.name() {
+        "libx264" | "libopenh264" | "libsvtav1" => {
+            (width.next_multiple_of(2), height.next_multiple_of(2))
+        }
+        _ => (width, height),
+    }
+}
+
+/// Tags the padding `coded_size` added as cropping, which Matroska stores as
+/// PixelCrop and MP4 as a clean aperture, so players show the window size.
+/// Windows links the FFmpeg in FFMPEG_DIR, which may predate cropping side
+/// data (FFmpeg 7.1), the bundled build always has it.
+#[cfg(not(target_os = "windows"))]
+fn attach_frame_cropping(
+    stream: &mut ffmpeg::format::stream::StreamMut,
+    coded: (u32, u32),
+    size: (u32, u32),
+) {
+    // top, bottom, left, right
+    let crop = [0, coded.1 - size.1, 0, coded.0 - size.0];
+    let len = crop.len() * size_of::<u32>();
+
+    unsafe {
+        let parameters = (*stream.as_mut_ptr()).codecpar;
+        let side_data = ffmpeg::ffi::av_packet_side_data_new(
+            &mut (*parameters).coded_side_data,
+            &mut (*parameters).nb_coded_side_data,
+            ffmpeg::ffi::AVPacketSideDataType::AV_PKT_DATA_FRAME_CROPPING,
+            len,
+            0,
+        );
+
+        if side_data.is_null() {
+            log_error!("Failed to allocate cropping side data");
+            return;
+        }
+
+        let data = std::slice::from_raw_parts_mut((*side_data).data, len);
+        for (bytes, value) in data.chunks_exact_mut(size_of::<u32>()).zip(crop) {
+            bytes.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+/// Attaches an encoded alpha plane as Matroska BlockAdditional side data, the
+/// layout is an 8 byte big endian BlockAddID followed by the VP9 payload
+fn attach_alpha_block_additional(
+    packet: &mut ffmpeg::packet::Packet,
+    alpha_packet: &ffmpeg::packet::Packet,
+) {
+    use ffmpeg::packet::Mut;
+
+    let Some(alpha_data) = alpha_packet.data() else {
+        return;
+    };
+
+    let id = ALPHA_BLOCK_ADD_ID.to_be_bytes();
+    let size = id.len() + alpha_data.len();
+
+    unsafe {
+        let side_data = ffmpeg::ffi::av_packet_new_side_data(
+            packet.as_mut_ptr(),
+            ffmpeg::ffi::AVPacketSideDataType::AV_PKT_DATA_MATROSKA_BLOCKADDITIONAL,
+            size,
+        );
+
+        if side_data.is_null() {
+            log_error!("Failed to allocate alpha side data");
+            return;
+        }
+
+        let side_data = std::slice::from_raw_parts_mut(side_data, size);
+        side_data[..id.len()].copy_from_slice(&id);
+        side_data[id.len()..].copy_from_slice(alpha_data);
+    }
 }
 
 struct SorkinExtension;
@@ -624,3 +1653,31 @@ unsafe impl ExtensionLibrary for SorkinExtension {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Speakers;
+
+    #[test]
+    fn audio_format_follows_the_pending_preset() {
+        let config = EncoderConfig::default();
+        assert_eq!(
+            audio::mix_rate(upcoming_config(&None, &config)),
+            audio::OPUS_SAMPLE_RATE
+        );
+
+        let pending = Some(EncoderConfig {
+            enable_audio: false,
+            ..EncoderConfig::default()
+        });
+        assert_eq!(audio::mix_rate(upcoming_config(&pending, &config)), 0);
+
+        let pending = Some(EncoderConfig {
+            speakers: Speakers::Surround51,
+            ..EncoderConfig::default()
+        });
+        let speakers = upcoming_config(&pending, &config).speakers;
+        assert_eq!(audio::channel_count(audio::speaker_mode(speakers)), 6);
+    }
+}
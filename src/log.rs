@@ -0,0 +1,64 @@
+//! Logging that is safe off the main thread. The offline passes run on a
+//! worker thread, which queues its messages for the main thread to print
+//! instead of calling into Godot.
+use std::{cell::RefCell, sync::mpsc::Sender};
+
+use godot::prelude::*;
+
+pub enum Level {
+    Print,
+    Warn,
+    Error,
+}
+
+pub type Message = (Level, String);
+
+thread_local! {
+    static QUEUE: RefCell<Option<Sender<Message>>> = const { RefCell::new(None) };
+}
+
+/// Queues this thread's messages on `queue` from now on
+pub fn redirect(queue: Sender<Message>) {
+    QUEUE.with(|q| *q.borrow_mut() = Some(queue));
+}
+
+/// Prints `message` through Godot, or queues it on a redirected thread
+pub fn log(level: Level, message: String) {
+    QUEUE.with(|queue| match queue.borrow().as_ref() {
+        // a closed queue means the main thread stopped listening, the
+        // message is dropped rather than printed from here
+        Some(queue) => {
+            let _ = queue.send((level, message));
+        }
+        None => emit(level, &message),
+    });
+}
+
+/// Prints through Godot, main thread only
+pub fn emit(level: Level, message: &str) {
+    match level {
+        Level::Print => godot_print!("{message}"),
+        Level::Warn => godot_warn!("{message}"),
+        Level::Error => godot_error!("{message}"),
+    }
+}
+
+macro_rules! log_print {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Print, format!($($arg)*))
+    };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Warn, format!($($arg)*))
+    };
+}
+
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Error, format!($($arg)*))
+    };
+}
+
+pub(crate) use {log_error, log_print, log_warn};
@@ -0,0 +1,55 @@
+//! Extra AVOptions from the project settings, merged over Sorkin's defaults.
+//! FFmpeg silently drops options nothing consumes, so every one is tried on
+//! its target first and the rejected ones are reported by name.
+use std::ffi::{c_void, CString};
+
+use ffmpeg_next::{self as ffmpeg, ffi};
+
+use crate::log::log_error;
+
+/// Adds `options` to `dict`, replacing defaults of the same name. `target` is
+/// the AVCodecContext or AVFormatContext the dictionary is applied to and
+/// `what` names it in error messages.
+///
+/// # Safety
+/// `target` must point to a live struct starting with an `AVClass` pointer
+pub unsafe fn merge(
+    dict: &mut ffmpeg::Dictionary,
+    target: *mut c_void,
+    options: &[(String, String)],
+    what: &str,
+) {
+    for (key, value) in options {
+        let (Ok(c_key), Ok(c_value)) = (CString::new(key.as_str()), CString::new(value.as_str()))
+        else {
+            log_error!("Ignoring {what} option {key}: contains a NUL byte");
+            continue;
+        };
+
+        // also searches the codec's or muxer's private options
+        let ret = unsafe {
+            ffi::av_opt_set(
+                target,
+                c_key.as_ptr(),
+                c_value.as_ptr(),
+                ffi::AV_OPT_SEARCH_CHILDREN as i32,
+            )
+        };
+        if ret < 0 {
+            log_error!(
+                "Ignoring {what} option {key}={value}: {}",
+                ffmpeg::Error::from(ret)
+            );
+            continue;
+        }
+
+        dict.set(key, value);
+    }
+}
+
+/// Reports whatever FFmpeg handed back unconsumed after opening
+pub fn report_unused(unused: &ffmpeg::Dictionary, what: &str) {
+    for (key, value) in unused.iter() {
+        log_error!("{what} did not use option {key}={value}");
+    }
+}
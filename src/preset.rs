@@ -0,0 +1,228 @@
+//! Named bundles of encoder settings stored as `.tres` files, so switching
+//! between e.g. a quick bug repro and a trailer master is a single setting.
+use godot::{engine::IResource, engine::Resource, prelude::*};
+
+use crate::settings::{AudioCodec, EncoderConfig, Quality, RateControl, VideoCodec};
+
+/// Built-in presets, usable by name wherever a preset path is accepted
+pub const BUILTIN_PRESETS: [&str; 4] = [
+    "Quick Bug Repro",
+    "Chat Upload",
+    "Trailer Master",
+    "Lossless Archive",
+];
+
+/// Codec, quality, rate control, audio and alpha settings that replace the
+/// project settings of the same name while the preset is active. Threading,
+/// keyframe and raw FFmpeg options still come from the project settings.
+#[derive(GodotClass)]
+#[class(base=Resource)]
+pub struct SorkinPreset {
+    /// AV1 needs Sorkin built with the av1 feature, FFV1 needs a .mkv file
+    #[export(enum = (VP9, FFV1, AV1))]
+    video_codec: i32,
+    #[export(enum = (Realtime, Good, Best))]
+    quality: i32,
+    #[export(enum = (ConstantQuality, ConstrainedQuality, VBR, CBR))]
+    rate_control: i32,
+    /// -1 uses the codec's default
+    #[export(range = (-1.0, 63.0))]
+    crf: i32,
+    #[export]
+    bitrate_kbps: i32,
+    #[export]
+    min_bitrate_kbps: i32,
+    #[export]
+    max_bitrate_kbps: i32,
+    #[export]
+    two_pass: bool,
+    /// 0 disables it
+    #[export]
+    target_size_mb: f64,
+    #[export]
+    enable_audio: bool,
+    /// FLAC needs a .mkv file
+    #[export(enum = (Opus, FLAC))]
+    audio_codec: i32,
+    #[export(enum = (AAC, Opus))]
+    mp4_audio_codec: i32,
+    #[export]
+    alpha_channel: bool,
+    #[export]
+    alpha_sidecar: bool,
+    base: Base<Resource>,
+}
+
+#[godot_api]
+impl IResource for SorkinPreset {
+    fn init(base: Base<Resource>) -> Self {
+        let mut preset = Self {
+            video_codec: 0,
+            quality: 0,
+            rate_control: 0,
+            crf: 0,
+            bitrate_kbps: 0,
+            min_bitrate_kbps: 0,
+            max_bitrate_kbps: 0,
+            two_pass: false,
+            target_size_mb: 0.0,
+            enable_audio: false,
+            audio_codec: 0,
+            mp4_audio_codec: 0,
+            alpha_channel: false,
+            alpha_sidecar: false,
+            base,
+        };
+        preset.copy_from(&EncoderConfig::default());
+        preset
+    }
+}
+
+#[godot_api]
+impl SorkinPreset {
+    /// One of the presets shipped with Sorkin, see `builtin_names`
+    #[func]
+    pub fn builtin(name: GString) -> Option<Gd<SorkinPreset>> {
+        let name = name.to_string();
+        let defaults = EncoderConfig::default();
+
+        let config = match name.as_str() {
+            "Quick Bug Repro" => EncoderConfig {
+                quality: Quality::Realtime,
+                crf: Some(40),
+                ..defaults
+            },
+            "Chat Upload" => EncoderConfig {
+                quality: Quality::Good,
+                target_size_mb: 8.0,
+                ..defaults
+            },
+            "Trailer Master" => EncoderConfig {
+                quality: Quality::Best,
+                rate_control: RateControl::ConstrainedQuality,
+                crf: Some(18),
+                bitrate_kbps: 40_000,
+                two_pass: true,
+                ..defaults
+            },
+            "Lossless Archive" => EncoderConfig {
+                quality: Quality::Best,
+                video_codec: VideoCodec::Ffv1,
+                audio_codec: AudioCodec::Flac,
+                ..defaults
+            },
+            _ => return None,
+        };
+
+        let mut preset = SorkinPreset::new_gd();
+        preset.bind_mut().copy_from(&config);
+        preset.set_name(name.into());
+        Some(preset)
+    }
+
+    #[func]
+    pub fn builtin_names() -> PackedStringArray {
+        let mut names = PackedStringArray::new();
+        for name in BUILTIN_PRESETS {
+            names.push(name.into());
+        }
+        names
+    }
+}
+
+impl SorkinPreset {
+    /// Resolves a built-in preset name or a resource path
+    pub fn load(name_or_path: &str) -> Option<Gd<SorkinPreset>> {
+        if let Some(preset) = Self::builtin(name_or_path.into()) {
+            return Some(preset);
+        }
+
+        match try_load::<SorkinPreset>(name_or_path) {
+            Ok(preset) => Some(preset),
+            Err(e) => {
+                godot_error!("Failed to load Sorkin preset {name_or_path}: {e}");
+                None
+            }
+        }
+    }
+
+    fn copy_from(&mut self, config: &EncoderConfig) {
+        self.video_codec = match config.video_codec {
+            VideoCodec::Ffv1 => 1,
+            #[cfg(feature = "av1")]
+            VideoCodec::Av1 => 2,
+            VideoCodec::Vp9 | VideoCodec::H264 => 0,
+        };
+        self.quality = match config.quality {
+            Quality::Realtime => 0,
+            Quality::Good => 1,
+            Quality::Best => 2,
+        };
+        self.rate_control = match config.rate_control {
+            RateControl::ConstantQuality => 0,
+            RateControl::ConstrainedQuality => 1,
+            RateControl::Vbr => 2,
+            RateControl::Cbr => 3,
+        };
+        self.crf = config.crf.map_or(-1, |crf| crf as i32);
+        self.bitrate_kbps = config.bitrate_kbps as i32;
+        self.min_bitrate_kbps = config.min_bitrate_kbps as i32;
+        self.max_bitrate_kbps = config.max_bitrate_kbps as i32;
+        self.two_pass = config.two_pass;
+        self.target_size_mb = config.target_size_mb;
+        self.enable_audio = config.enable_audio;
+        self.audio_codec = match config.audio_codec {
+            AudioCodec::Flac => 1,
+            AudioCodec::Opus | AudioCodec::Aac => 0,
+        };
+        self.mp4_audio_codec = match config.mp4_audio_codec {
+            AudioCodec::Opus => 1,
+            AudioCodec::Aac | AudioCodec::Flac => 0,
+        };
+        self.alpha_channel = config.alpha_channel;
+        self.alpha_sidecar = config.alpha_sidecar;
+    }
+
+    /// Overrides the settings this preset covers
+    pub fn apply(&self, config: &mut EncoderConfig) {
+        config.video_codec = match self.video_codec {
+            1 => VideoCodec::Ffv1,
+            #[cfg(feature = "av1")]
+            2 => VideoCodec::Av1,
+            #[cfg(not(feature = "av1"))]
+            2 => {
+                godot_warn!("Sorkin was built without the av1 feature, using VP9 instead");
+                VideoCodec::Vp9
+            }
+            _ => VideoCodec::Vp9,
+        };
+        config.quality = match self.quality {
+            1 => Quality::Good,
+            2 => Quality::Best,
+            _ => Quality::Realtime,
+        };
+        config.rate_control = match self.rate_control {
+            1 => RateControl::ConstrainedQuality,
+            2 => RateControl::Vbr,
+            3 => RateControl::Cbr,
+            _ => RateControl::ConstantQuality,
+        };
+        config.crf = u32::try_from(self.crf).ok();
+        config.bitrate_kbps = self.bitrate_kbps.max(0) as u32;
+        config.min_bitrate_kbps = self.min_bitrate_kbps.max(0) as u32;
+        config.max_bitrate_kbps = self.max_bitrate_kbps.max(0) as u32;
+        config.two_pass = self.two_pass;
+        config.target_size_mb = self.target_size_mb.max(0.0);
+        config.enable_audio = self.enable_audio;
+        config.audio_codec = match self.audio_codec {
+            1 => AudioCodec::Flac,
+            _ => AudioCodec::Opus,
+        };
+        config.mp4_audio_codec = match self.mp4_audio_codec {
+            1 => AudioCodec::Opus,
+            _ => AudioCodec::Aac,
+        };
+        config.alpha_channel = self.alpha_channel;
+        config.alpha_sidecar = self.alpha_sidecar;
+    }
+}
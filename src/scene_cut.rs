@@ -0,0 +1,64 @@
+//! Detects hard cuts by comparing the luma planes of consecutive frames, so
+//! a keyframe can be placed exactly on them.
+use ffmpeg_next::util::frame::Video;
+
+use crate::conversion;
+
+/// Only every 4th pixel of every 4th row is compared, plenty to spot a cut
+const SAMPLE_STEP: usize = 4;
+
+pub struct SceneCutDetector {
+    /// Mean absolute luma difference, 0 to 1, above which a frame is a cut
+    threshold: f64,
+    previous: Vec<u8>,
+    current: Vec<u8>,
+}
+
+impl SceneCutDetector {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            previous: Vec::new(),
+            current: Vec::new(),
+        }
+    }
+
+    /// Whether `frame` differs from the frame passed in the previous call by
+    /// more than the threshold, the first frame never counts as a cut
+    pub fn is_cut(&mut self, frame: &Video) -> bool {
+        let width = frame.width() as usize;
+        let stride = frame.stride(0);
+        let luma = frame.data(0);
+        let ten_bit = conversion::is_ten_bit(frame.format());
+
+        self.current.clear();
+        for row in (0..frame.height() as usize).step_by(SAMPLE_STEP) {
+            let start = row * stride;
+            if ten_bit {
+                // the top 8 of the 10 bits are plenty to compare
+                self.current.extend(
+                    luma[start..start + width * 2]
+                        .chunks_exact(2)
+                        .step_by(SAMPLE_STEP)
+                        .map(|s| (u16::from_le_bytes([s[0], s[1]]) >> 2) as u8),
+                );
+            } else {
+                self.current
+                    .extend(luma[start..start + width].iter().step_by(SAMPLE_STEP));
+            }
+        }
+
+        let cut = !self.current.is_empty() && self.current.len() == self.previous.len() && {
+            let difference: u64 = self
+                .current
+                .iter()
+                .zip(&self.previous)
+                .map(|(a, b)| a.abs_diff(*b) as u64)
+                .sum();
+            difference as f64 / self.current.len() as f64 / 255.0 > self.threshold
+        };
+
+        std::mem::swap(&mut self.previous, &mut self.current);
+        cut
+    }
+}
@@ -3,14 +3,115 @@ use godot::{engine::ProjectSettings, global::PropertyHint, prelude::*};
 const SETTING_THREAD_COUNT: &str = "sorkin_movie_writer/thread_count";
 const SETTING_QUALITY: &str = "sorkin_movie_writer/quality";
 const SETTING_ALPHA_CHANNEL: &str = "sorkin_movie_writer/alpha_channel";
+const SETTING_ALPHA_SIDECAR: &str = "sorkin_movie_writer/alpha_sidecar";
 const SETTING_ENABLE_AUDIO: &str = "sorkin_movie_writer/enable_audio";
+const SETTING_VIDEO_CODEC: &str = "sorkin_movie_writer/video_codec";
+const SETTING_AUDIO_CODEC: &str = "sorkin_movie_writer/audio_codec";
+const SETTING_MP4_AUDIO_CODEC: &str = "sorkin_movie_writer/mp4_audio_codec";
+const SETTING_RATE_CONTROL: &str = "sorkin_movie_writer/rate_control";
+const SETTING_CRF: &str = "sorkin_movie_writer/crf";
+const SETTING_BITRATE: &str = "sorkin_movie_writer/bitrate_kbps";
+const SETTING_MIN_BITRATE: &str = "sorkin_movie_writer/min_bitrate_kbps";
+const SETTING_MAX_BITRATE: &str = "sorkin_movie_writer/max_bitrate_kbps";
+const SETTING_TWO_PASS: &str = "sorkin_movie_writer/two_pass";
+const SETTING_TARGET_SIZE: &str = "sorkin_movie_writer/target_size_mb";
+const SETTING_KEYFRAME_INTERVAL: &str = "sorkin_movie_writer/keyframe_interval";
+const SETTING_MIN_KEYFRAME_INTERVAL: &str = "sorkin_movie_writer/min_keyframe_interval";
+const SETTING_KEYFRAME_UNIT: &str = "sorkin_movie_writer/keyframe_interval_unit";
+const SETTING_SCENE_CUT_THRESHOLD: &str = "sorkin_movie_writer/scene_cut_threshold";
+const SETTING_VIDEO_OPTIONS: &str = "sorkin_movie_writer/video_options";
+const SETTING_AUDIO_OPTIONS: &str = "sorkin_movie_writer/audio_options";
+const SETTING_MUXER_OPTIONS: &str = "sorkin_movie_writer/muxer_options";
+const SETTING_PRESET: &str = "sorkin_movie_writer/preset";
+const SETTING_BIT_DEPTH: &str = "sorkin_movie_writer/bit_depth";
+const SETTING_HDR_TRANSFER: &str = "sorkin_movie_writer/hdr_transfer";
+const SETTING_CHROMA_SUBSAMPLING: &str = "sorkin_movie_writer/chroma_subsampling";
+const SETTING_CHROMA_FILTER: &str = "sorkin_movie_writer/chroma_filter";
+const SETTING_COLOR_MATRIX: &str = "sorkin_movie_writer/color_matrix";
+const SETTING_COLOR_RANGE: &str = "sorkin_movie_writer/color_range";
+const SETTING_OUTPUT_WIDTH: &str = "sorkin_movie_writer/output_width";
+const SETTING_OUTPUT_HEIGHT: &str = "sorkin_movie_writer/output_height";
+const SETTING_OUTPUT_SCALE: &str = "sorkin_movie_writer/output_scale";
+const SETTING_SCALE_FILTER: &str = "sorkin_movie_writer/scale_filter";
+const SETTING_PIXEL_ART_SCALE: &str = "sorkin_movie_writer/pixel_art_scale";
+const SETTING_CAPTURE_RECT: &str = "sorkin_movie_writer/capture_rect";
+const SETTING_CAPTURE_SMOOTHING: &str = "sorkin_movie_writer/capture_smoothing";
+const SETTING_FIT_MODE: &str = "sorkin_movie_writer/fit_mode";
+const SETTING_PAD_COLOR: &str = "sorkin_movie_writer/pad_color";
+const SETTING_RESIZE_POLICY: &str = "sorkin_movie_writer/resize_policy";
+const SETTING_FRAME_RATE: &str = "sorkin_movie_writer/frame_rate";
+const SETTING_SPEAKER_MODE: &str = "sorkin_movie_writer/speaker_mode";
+
+/// Keyframe interval used to keep a matte aligned when none is set
+const ALPHA_KEYFRAME_SECONDS: f64 = 2.0;
 
 #[derive(Clone, Debug)]
 pub struct EncoderConfig {
     pub thread_count: u32,
     pub quality: Quality,
     pub alpha_channel: bool,
+    /// Write the matte to a separate `_alpha.webm` instead of embedding it
+    pub alpha_sidecar: bool,
     pub enable_audio: bool,
+    pub video_codec: VideoCodec,
+    pub audio_codec: AudioCodec,
+    /// Audio codec for .mp4/.mov output, where video is always H.264
+    pub mp4_audio_codec: AudioCodec,
+    pub rate_control: RateControl,
+    /// Constant rate factor, lower is better. Ignored by VBR and CBR.
+    /// `None` uses the codec's `default_crf`.
+    pub crf: Option<u32>,
+    /// Target bitrate, also the ceiling in constrained quality mode
+    pub bitrate_kbps: u32,
+    /// 0 leaves the bound to the encoder
+    pub min_bitrate_kbps: u32,
+    /// 0 leaves the bound to the encoder
+    pub max_bitrate_kbps: u32,
+    /// Store frames losslessly and encode them twice once recording ends
+    pub two_pass: bool,
+    /// Size in MB the finished file has to fit in, 0 disables it
+    pub target_size_mb: f64,
+    /// Longest distance between keyframes, 0 leaves it to the encoder
+    pub keyframe_interval: f64,
+    /// Shortest distance between keyframes, 0 leaves it to the encoder
+    pub min_keyframe_interval: f64,
+    pub keyframe_unit: KeyframeUnit,
+    /// Mean luma change between frames, 0 to 1, that forces a keyframe. 0 disables it
+    pub scene_cut_threshold: f64,
+    /// Extra AVOptions for the video encoder, applied over Sorkin's defaults
+    pub video_options: Vec<(String, String)>,
+    /// Extra AVOptions for the audio encoder, applied over Sorkin's defaults
+    pub audio_options: Vec<(String, String)>,
+    /// Extra AVOptions for the muxer, applied over Sorkin's defaults
+    pub muxer_options: Vec<(String, String)>,
+    pub bit_depth: BitDepth,
+    /// HDR output, requires 10-bit
+    pub transfer: Transfer,
+    pub chroma_subsampling: ChromaSubsampling,
+    pub chroma_filter: ChromaFilter,
+    /// RGB to YUV matrix for SDR output, HDR always uses BT.2020
+    pub color_matrix: ColorMatrix,
+    pub color_range: ColorRange,
+    /// Encoded size, 0 derives the dimension from the other one or from `output_scale`
+    pub output_width: u32,
+    pub output_height: u32,
+    /// Factor applied to the movie size when no output size is set
+    pub output_scale: f64,
+    pub scale_filter: ScaleFilter,
+    /// Integer nearest neighbor upscale for pixel art, 0 or 1 disables it
+    pub pixel_art_scale: u32,
+    /// Region of the movie that gets recorded, `None` for all of it
+    pub capture_rect: Option<Rect2i>,
+    /// Seconds `set_capture_rect` changes are eased over
+    pub capture_smoothing: f64,
+    /// How the captured region is fit into an output of another aspect ratio
+    pub fit_mode: FitMode,
+    pub pad_color: Color,
+    pub resize_policy: ResizePolicy,
+    /// Output frame rate, `None` uses Movie Maker's fixed fps
+    pub frame_rate: Option<ffmpeg_next::Rational>,
+    /// Speaker layout Godot mixes the recorded audio in
+    pub speakers: Speakers,
 }
 
 #[derive(Clone, Debug)]
@@ -20,13 +121,280 @@ pub enum Quality {
     Best,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateControl {
+    /// Only the CRF matters, file size is unbounded
+    ConstantQuality,
+    /// CRF, but never above `bitrate_kbps`
+    ConstrainedQuality,
+    Vbr,
+    Cbr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitDepth {
+    Eight,
+    /// VP9 profile 2, AV1 main or FFV1 with 10 bits per sample
+    Ten,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    /// Half resolution color, what every player expects
+    Yuv420,
+    /// Full resolution color for pixel art and UI text, VP9 profile 1
+    Yuv444,
+}
+
+/// How each 2x2 block is reduced to one chroma sample in 4:2:0
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChromaFilter {
+    /// 1/4 1/2 1/4 horizontally, sited on the left column like MPEG-2 and H.264
+    Bilinear,
+    /// Average of the block, sited in its center
+    Box,
+    /// Top left pixel only, the original behavior
+    Fast,
+}
+
+/// Resampling filter used when the output size differs from the movie size
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScaleFilter {
+    Nearest,
+    Bilinear,
+    /// Catmull-Rom
+    Bicubic,
+    /// Lanczos3, sharpest but can ring around hard edges
+    Lanczos,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FitMode {
+    /// Scale the whole region to the output size, distorting it
+    Stretch,
+    /// Letterbox or pillarbox with the pad color
+    Pad,
+    /// Fill the output and cut off what sticks out
+    Crop,
+}
+
+/// What happens when the movie size changes while recording
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResizePolicy {
+    /// Keep the output size and fit the new frames into it
+    Rescale,
+    /// Finish the file and continue in a numbered one at the new size
+    NewFile,
+}
+
+/// Speaker layout of the recorded audio. Movie Maker mixes through the dummy
+/// audio driver, which always reports stereo, so it is picked explicitly
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speakers {
+    Stereo,
+    Surround51,
+    Surround71,
+}
+
+/// RGB to YUV conversion matrix, written to the stream as its colorspace
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMatrix {
+    Bt601,
+    Bt709,
+    /// Non-constant luminance
+    Bt2020,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorRange {
+    /// 16-235, what players assume for untagged video
+    Limited,
+    /// 0-255
+    Full,
+}
+
+impl ColorMatrix {
+    /// Red and blue luma weights, Kr and Kb
+    pub fn coefficients(self) -> (f32, f32) {
+        match self {
+            ColorMatrix::Bt601 => (0.299, 0.114),
+            ColorMatrix::Bt709 => (0.2126, 0.0722),
+            ColorMatrix::Bt2020 => (0.2627, 0.0593),
+        }
+    }
+}
+
+/// Transfer function of the output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transfer {
+    /// sRGB-like, tagged as nothing in particular
+    Sdr,
+    /// BT.2020 with SMPTE ST 2084, HDR10
+    Pq,
+    /// BT.2020 with ARIB STD-B67 hybrid log-gamma
+    Hlg,
+}
+
+/// What the keyframe intervals are measured in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyframeUnit {
+    Frames,
+    Seconds,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoCodec {
+    Vp9,
+    /// Lossless, only allowed in Matroska
+    Ffv1,
+    #[cfg(feature = "av1")]
+    Av1,
+    /// Only used for MP4/MOV output
+    H264,
+}
+
+/// Codecs offered in the editor. AV1 is only listed when the linked FFmpeg
+/// has an encoder for it, the bundled build doesn't include one.
+fn video_codec_hint() -> &'static str {
+    #[cfg(feature = "av1")]
+    {
+        if ffmpeg_next::init().is_ok() && VideoCodec::Av1.find_encoder().is_some() {
+            return "VP9,AV1,FFV1";
+        }
+    }
+    "VP9,FFV1"
+}
+
+/// AV1 encoders in order of preference, SVT-AV1 is much faster at equal quality
+#[cfg(feature = "av1")]
+pub const AV1_ENCODERS: [&str; 2] = ["libsvtav1", "libaom-av1"];
+
+/// Software H.264 encoders in order of preference, any other registered
+/// H.264 encoder (e.g. a hardware one) is used as a last resort
+pub const H264_ENCODERS: [&str; 2] = ["libx264", "libopenh264"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioCodec {
+    Opus,
+    /// Lossless, only allowed in Matroska
+    Flac,
+    /// Only used for MP4/MOV output
+    Aac,
+}
+
+impl VideoCodec {
+    /// CRF used when the setting is left at -1. x264 at 32 is visibly soft,
+    /// its usual default of 23 matches VP9 at 32 more closely.
+    pub fn default_crf(self) -> u32 {
+        match self {
+            VideoCodec::H264 => 23,
+            _ => 32,
+        }
+    }
+
+    pub fn id(self) -> ffmpeg_next::codec::Id {
+        match self {
+            VideoCodec::Vp9 => ffmpeg_next::codec::Id::VP9,
+            VideoCodec::Ffv1 => ffmpeg_next::codec::Id::FFV1,
+            #[cfg(feature = "av1")]
+            VideoCodec::Av1 => ffmpeg_next::codec::Id::AV1,
+            VideoCodec::H264 => ffmpeg_next::codec::Id::H264,
+        }
+    }
+
+    /// Looks up an encoder implementation in the linked FFmpeg
+    pub fn find_encoder(self) -> Option<ffmpeg_next::Codec> {
+        match self {
+            #[cfg(feature = "av1")]
+            VideoCodec::Av1 => AV1_ENCODERS
+                .iter()
+                .find_map(|name| ffmpeg_next::encoder::find_by_name(name)),
+            VideoCodec::H264 => H264_ENCODERS
+                .iter()
+                .find_map(|name| ffmpeg_next::encoder::find_by_name(name))
+                .or_else(|| ffmpeg_next::encoder::find(self.id())),
+            _ => ffmpeg_next::encoder::find(self.id()),
+        }
+    }
+
+    /// Whether the encoder `find_encoder` picks can store full resolution
+    /// chroma, H.264 output is 4:2:0 and SVT-AV1 only takes 4:2:0 input
+    pub fn supports_yuv444(self) -> bool {
+        match self {
+            VideoCodec::H264 => false,
+            #[cfg(feature = "av1")]
+            VideoCodec::Av1 => !self
+                .find_encoder()
+                .is_some_and(|codec| codec.name() == "libsvtav1"),
+            _ => true,
+        }
+    }
+
+    /// Whether the WebM subset of Matroska permits this codec
+    pub fn webm_compatible(self) -> bool {
+        !matches!(self, VideoCodec::Ffv1 | VideoCodec::H264)
+    }
+}
+
+impl AudioCodec {
+    pub fn id(self) -> ffmpeg_next::codec::Id {
+        match self {
+            AudioCodec::Opus => ffmpeg_next::codec::Id::OPUS,
+            AudioCodec::Flac => ffmpeg_next::codec::Id::FLAC,
+            AudioCodec::Aac => ffmpeg_next::codec::Id::AAC,
+        }
+    }
+
+    /// Whether the WebM subset of Matroska permits this codec
+    pub fn webm_compatible(self) -> bool {
+        matches!(self, AudioCodec::Opus)
+    }
+}
+
 impl Default for EncoderConfig {
     fn default() -> Self {
         Self {
             thread_count: 0, // 0 = auto-detect
             quality: Quality::Realtime,
             alpha_channel: false,
+            alpha_sidecar: false,
             enable_audio: true,
+            video_codec: VideoCodec::Vp9,
+            audio_codec: AudioCodec::Opus,
+            mp4_audio_codec: AudioCodec::Aac,
+            // libvpx falls back to CRF 32 when nothing is set, keep that default
+            rate_control: RateControl::ConstantQuality,
+            crf: None,
+            bitrate_kbps: 8000,
+            min_bitrate_kbps: 0,
+            max_bitrate_kbps: 0,
+            two_pass: false,
+            target_size_mb: 0.0,
+            keyframe_interval: 0.0,
+            min_keyframe_interval: 0.0,
+            keyframe_unit: KeyframeUnit::Frames,
+            scene_cut_threshold: 0.0,
+            video_options: Vec::new(),
+            audio_options: Vec::new(),
+            muxer_options: Vec::new(),
+            bit_depth: BitDepth::Eight,
+            transfer: Transfer::Sdr,
+            chroma_subsampling: ChromaSubsampling::Yuv420,
+            chroma_filter: ChromaFilter::Bilinear,
+            color_matrix: ColorMatrix::Bt709,
+            color_range: ColorRange::Limited,
+            output_width: 0,
+            output_height: 0,
+            output_scale: 1.0,
+            scale_filter: ScaleFilter::Lanczos,
+            pixel_art_scale: 0,
+            capture_rect: None,
+            capture_smoothing: 0.2,
+            fit_mode: FitMode::Pad,
+            pad_color: Color::BLACK,
+            resize_policy: ResizePolicy::Rescale,
+            frame_rate: None,
+            speakers: Speakers::Stereo,
         }
     }
 }
@@ -57,20 +425,578 @@ impl EncoderConfig {
             .ok()
             .unwrap_or(false);
 
+        let alpha_sidecar = project_settings
+            .get_setting(SETTING_ALPHA_SIDECAR.into())
+            .try_to::<bool>()
+            .ok()
+            .unwrap_or(false);
+
         let enable_audio = project_settings
             .get_setting(SETTING_ENABLE_AUDIO.into())
             .try_to::<bool>()
             .ok()
             .unwrap_or(true);
 
-        Self {
+        let video_codec = project_settings
+            .get_setting(SETTING_VIDEO_CODEC.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "FFV1" => VideoCodec::Ffv1,
+                #[cfg(feature = "av1")]
+                "AV1" => VideoCodec::Av1,
+                _ => VideoCodec::Vp9,
+            })
+            .unwrap_or(VideoCodec::Vp9);
+
+        let audio_codec = project_settings
+            .get_setting(SETTING_AUDIO_CODEC.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "FLAC" => AudioCodec::Flac,
+                _ => AudioCodec::Opus,
+            })
+            .unwrap_or(AudioCodec::Opus);
+
+        let mp4_audio_codec = project_settings
+            .get_setting(SETTING_MP4_AUDIO_CODEC.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "Opus" => AudioCodec::Opus,
+                _ => AudioCodec::Aac,
+            })
+            .unwrap_or(AudioCodec::Aac);
+
+        let rate_control = project_settings
+            .get_setting(SETTING_RATE_CONTROL.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "Constrained Quality" => RateControl::ConstrainedQuality,
+                "VBR" => RateControl::Vbr,
+                "CBR" => RateControl::Cbr,
+                _ => RateControl::ConstantQuality,
+            })
+            .unwrap_or(RateControl::ConstantQuality);
+
+        let defaults = Self::default();
+
+        let crf = project_settings
+            .get_setting(SETTING_CRF.into())
+            .try_to::<i32>()
+            .map(|crf| u32::try_from(crf).ok())
+            .unwrap_or(defaults.crf);
+
+        let bitrate_kbps = project_settings
+            .get_setting(SETTING_BITRATE.into())
+            .try_to::<u32>()
+            .unwrap_or(defaults.bitrate_kbps);
+
+        let min_bitrate_kbps = project_settings
+            .get_setting(SETTING_MIN_BITRATE.into())
+            .try_to::<u32>()
+            .unwrap_or(defaults.min_bitrate_kbps);
+
+        let max_bitrate_kbps = project_settings
+            .get_setting(SETTING_MAX_BITRATE.into())
+            .try_to::<u32>()
+            .unwrap_or(defaults.max_bitrate_kbps);
+
+        let two_pass = project_settings
+            .get_setting(SETTING_TWO_PASS.into())
+            .try_to::<bool>()
+            .ok()
+            .unwrap_or(false);
+
+        let target_size_mb = project_settings
+            .get_setting(SETTING_TARGET_SIZE.into())
+            .try_to::<f64>()
+            .ok()
+            .unwrap_or(0.0)
+            .max(0.0);
+
+        let keyframe_interval = project_settings
+            .get_setting(SETTING_KEYFRAME_INTERVAL.into())
+            .try_to::<f64>()
+            .unwrap_or(defaults.keyframe_interval)
+            .max(0.0);
+
+        let min_keyframe_interval = project_settings
+            .get_setting(SETTING_MIN_KEYFRAME_INTERVAL.into())
+            .try_to::<f64>()
+            .unwrap_or(defaults.min_keyframe_interval)
+            .max(0.0);
+
+        let keyframe_unit = project_settings
+            .get_setting(SETTING_KEYFRAME_UNIT.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "Seconds" => KeyframeUnit::Seconds,
+                _ => KeyframeUnit::Frames,
+            })
+            .unwrap_or(KeyframeUnit::Frames);
+
+        let scene_cut_threshold = project_settings
+            .get_setting(SETTING_SCENE_CUT_THRESHOLD.into())
+            .try_to::<f64>()
+            .unwrap_or(defaults.scene_cut_threshold)
+            .clamp(0.0, 1.0);
+
+        let read_options = |setting: &str| {
+            project_settings
+                .get_setting(setting.into())
+                .try_to::<Dictionary>()
+                .map(|options| {
+                    options
+                        .iter_shared()
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let video_options = read_options(SETTING_VIDEO_OPTIONS);
+        let audio_options = read_options(SETTING_AUDIO_OPTIONS);
+        let muxer_options = read_options(SETTING_MUXER_OPTIONS);
+
+        let bit_depth = project_settings
+            .get_setting(SETTING_BIT_DEPTH.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "10-bit" => BitDepth::Ten,
+                _ => BitDepth::Eight,
+            })
+            .unwrap_or(BitDepth::Eight);
+
+        let transfer = project_settings
+            .get_setting(SETTING_HDR_TRANSFER.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "PQ" => Transfer::Pq,
+                "HLG" => Transfer::Hlg,
+                _ => Transfer::Sdr,
+            })
+            .unwrap_or(Transfer::Sdr);
+
+        let chroma_subsampling = project_settings
+            .get_setting(SETTING_CHROMA_SUBSAMPLING.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "4:4:4" => ChromaSubsampling::Yuv444,
+                _ => ChromaSubsampling::Yuv420,
+            })
+            .unwrap_or(ChromaSubsampling::Yuv420);
+
+        let chroma_filter = project_settings
+            .get_setting(SETTING_CHROMA_FILTER.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "Box" => ChromaFilter::Box,
+                "Fast" => ChromaFilter::Fast,
+                _ => ChromaFilter::Bilinear,
+            })
+            .unwrap_or(ChromaFilter::Bilinear);
+
+        let color_matrix = project_settings
+            .get_setting(SETTING_COLOR_MATRIX.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "BT.601" => ColorMatrix::Bt601,
+                "BT.2020" => ColorMatrix::Bt2020,
+                _ => ColorMatrix::Bt709,
+            })
+            .unwrap_or(ColorMatrix::Bt709);
+
+        let color_range = project_settings
+            .get_setting(SETTING_COLOR_RANGE.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "Full" => ColorRange::Full,
+                _ => ColorRange::Limited,
+            })
+            .unwrap_or(ColorRange::Limited);
+
+        let output_width = project_settings
+            .get_setting(SETTING_OUTPUT_WIDTH.into())
+            .try_to::<u32>()
+            .unwrap_or(defaults.output_width);
+
+        let output_height = project_settings
+            .get_setting(SETTING_OUTPUT_HEIGHT.into())
+            .try_to::<u32>()
+            .unwrap_or(defaults.output_height);
+
+        let output_scale = project_settings
+            .get_setting(SETTING_OUTPUT_SCALE.into())
+            .try_to::<f64>()
+            .ok()
+            .filter(|scale| *scale > 0.0)
+            .unwrap_or(defaults.output_scale);
+
+        let scale_filter = project_settings
+            .get_setting(SETTING_SCALE_FILTER.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "Nearest" => ScaleFilter::Nearest,
+                "Bilinear" => ScaleFilter::Bilinear,
+                "Bicubic" => ScaleFilter::Bicubic,
+                _ => ScaleFilter::Lanczos,
+            })
+            .unwrap_or(defaults.scale_filter);
+
+        let pixel_art_scale = project_settings
+            .get_setting(SETTING_PIXEL_ART_SCALE.into())
+            .try_to::<u32>()
+            .unwrap_or(defaults.pixel_art_scale);
+
+        let capture_rect = project_settings
+            .get_setting(SETTING_CAPTURE_RECT.into())
+            .try_to::<Rect2i>()
+            .ok()
+            .filter(|rect| rect.size.x > 0 && rect.size.y > 0);
+
+        let capture_smoothing = project_settings
+            .get_setting(SETTING_CAPTURE_SMOOTHING.into())
+            .try_to::<f64>()
+            .ok()
+            .unwrap_or(defaults.capture_smoothing)
+            .max(0.0);
+
+        let fit_mode = project_settings
+            .get_setting(SETTING_FIT_MODE.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "Stretch" => FitMode::Stretch,
+                "Crop" => FitMode::Crop,
+                _ => FitMode::Pad,
+            })
+            .unwrap_or(defaults.fit_mode);
+
+        let pad_color = project_settings
+            .get_setting(SETTING_PAD_COLOR.into())
+            .try_to::<Color>()
+            .unwrap_or(defaults.pad_color);
+
+        let resize_policy = project_settings
+            .get_setting(SETTING_RESIZE_POLICY.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "New File" => ResizePolicy::NewFile,
+                _ => ResizePolicy::Rescale,
+            })
+            .unwrap_or(defaults.resize_policy);
+
+        let frame_rate = project_settings
+            .get_setting(SETTING_FRAME_RATE.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| s.to_string())
+            .filter(|s| !s.trim().is_empty())
+            .and_then(|s| {
+                let rate = parse_frame_rate(&s);
+                if rate.is_none() {
+                    godot_warn!("Invalid frame rate {s:?}, using Movie Maker's fps");
+                }
+                rate
+            })
+            .or(defaults.frame_rate);
+
+        let speakers = project_settings
+            .get_setting(SETTING_SPEAKER_MODE.into())
+            .try_to::<GString>()
+            .ok()
+            .map(|s| match s.to_string().as_str() {
+                "Stereo" => Speakers::Stereo,
+                "5.1" => Speakers::Surround51,
+                "7.1" => Speakers::Surround71,
+                _ => Speakers::Stereo,
+            })
+            .unwrap_or(defaults.speakers);
+
+        let mut config = Self {
             thread_count,
             quality,
             alpha_channel,
+            alpha_sidecar,
             enable_audio,
+            video_codec,
+            audio_codec,
+            mp4_audio_codec,
+            rate_control,
+            crf,
+            bitrate_kbps,
+            min_bitrate_kbps,
+            max_bitrate_kbps,
+            two_pass,
+            target_size_mb,
+            keyframe_interval,
+            min_keyframe_interval,
+            keyframe_unit,
+            scene_cut_threshold,
+            video_options,
+            audio_options,
+            muxer_options,
+            bit_depth,
+            transfer,
+            chroma_subsampling,
+            chroma_filter,
+            color_matrix,
+            color_range,
+            output_width,
+            output_height,
+            output_scale,
+            scale_filter,
+            pixel_art_scale,
+            capture_rect,
+            capture_smoothing,
+            fit_mode,
+            pad_color,
+            resize_policy,
+            frame_rate,
+            speakers,
+        };
+
+        let preset = project_settings
+            .get_setting(SETTING_PRESET.into())
+            .try_to::<GString>()
+            .map(|s| s.to_string())
+            .unwrap_or_default();
+        if !preset.is_empty() {
+            if let Some(preset) = crate::preset::SorkinPreset::load(&preset) {
+                preset.bind().apply(&mut config);
+            }
+        }
+
+        config
+    }
+
+    /// Position of the chroma samples produced by the chroma filter
+    pub fn chroma_location(&self) -> ffmpeg_next::util::chroma::Location {
+        use ffmpeg_next::util::chroma::Location;
+        match (self.chroma_subsampling, self.chroma_filter) {
+            (ChromaSubsampling::Yuv444, _) => Location::Unspecified,
+            (ChromaSubsampling::Yuv420, ChromaFilter::Bilinear) => Location::Left,
+            (ChromaSubsampling::Yuv420, ChromaFilter::Box) => Location::Center,
+            (ChromaSubsampling::Yuv420, ChromaFilter::Fast) => Location::TopLeft,
+        }
+    }
+
+    /// Matrix actually used, HDR output is always BT.2020
+    pub fn matrix(&self) -> ColorMatrix {
+        match self.transfer {
+            Transfer::Sdr => self.color_matrix,
+            Transfer::Pq | Transfer::Hlg => ColorMatrix::Bt2020,
         }
     }
 
+    /// Pixel format of the frames handed to the video encoder
+    pub fn pixel_format(&self) -> ffmpeg_next::format::Pixel {
+        match (self.bit_depth, self.chroma_subsampling) {
+            (BitDepth::Eight, ChromaSubsampling::Yuv420) => ffmpeg_next::format::Pixel::YUV420P,
+            (BitDepth::Ten, ChromaSubsampling::Yuv420) => ffmpeg_next::format::Pixel::YUV420P10LE,
+            (BitDepth::Eight, ChromaSubsampling::Yuv444) => ffmpeg_next::format::Pixel::YUV444P,
+            (BitDepth::Ten, ChromaSubsampling::Yuv444) => ffmpeg_next::format::Pixel::YUV444P10LE,
+        }
+    }
+
+    /// Whether the codec stores the matte as a fourth plane of the video
+    /// stream instead of a second encoder
+    pub fn native_alpha(&self) -> bool {
+        self.alpha_channel && !self.alpha_sidecar && self.video_codec == VideoCodec::Ffv1
+    }
+
+    /// Pixel format the video encoder is opened with, `pixel_format` plus an
+    /// alpha plane where the codec stores it natively
+    pub fn encoder_pixel_format(&self) -> ffmpeg_next::format::Pixel {
+        use ffmpeg_next::format::Pixel;
+        if !self.native_alpha() {
+            return self.pixel_format();
+        }
+        match self.pixel_format() {
+            Pixel::YUV420P10LE => Pixel::YUVA420P10LE,
+            Pixel::YUV444P => Pixel::YUVA444P,
+            Pixel::YUV444P10LE => Pixel::YUVA444P10LE,
+            _ => Pixel::YUVA420P,
+        }
+    }
+
+    /// CRF for the configured codec
+    pub fn crf(&self) -> u32 {
+        self.crf.unwrap_or(self.video_codec.default_crf())
+    }
+
+    /// Settings of an encoder that only carries the matte. It has no audio or
+    /// matte of its own and is tagged full range, alpha is stored as 0-255
+    /// codes whatever range the color is in.
+    pub fn matte_config(&self) -> Self {
+        Self {
+            enable_audio: false,
+            alpha_channel: false,
+            color_range: ColorRange::Full,
+            ..self.clone()
+        }
+    }
+
+    /// Places keyframes at a fixed interval, every `ALPHA_KEYFRAME_SECONDS`
+    /// when none is set, if a separately encoded matte is recorded. The color
+    /// and alpha encoders would each pick keyframes on their own content
+    /// otherwise and the two GOP structures have to stay identical.
+    pub fn with_aligned_keyframes(mut self) -> Self {
+        if !self.alpha_channel || self.native_alpha() {
+            return self;
+        }
+
+        if self.keyframe_interval <= 0.0 {
+            self.keyframe_interval = ALPHA_KEYFRAME_SECONDS;
+            self.keyframe_unit = KeyframeUnit::Seconds;
+        }
+        self.min_keyframe_interval = self.keyframe_interval;
+        self
+    }
+
+    /// Maximum and minimum keyframe distance in frames, 0 where unset
+    pub fn keyframe_interval_frames(&self, fps: f64) -> (u32, u32) {
+        let to_frames = |interval: f64| match self.keyframe_unit {
+            KeyframeUnit::Frames => interval.round() as u32,
+            KeyframeUnit::Seconds => (interval * fps).round() as u32,
+        };
+
+        (
+            to_frames(self.keyframe_interval),
+            to_frames(self.min_keyframe_interval),
+        )
+    }
+
+    pub fn validate_keyframe_interval(&self) -> Result<(), crate::Error> {
+        if self.keyframe_interval > 0.0 && self.min_keyframe_interval > self.keyframe_interval {
+            return Err(crate::Error::Encoding(format!(
+                "Minimum keyframe interval {} is above the maximum of {}",
+                self.min_keyframe_interval, self.keyframe_interval
+            )));
+        }
+
+        // libvpx ignores keyint_min unless it matches g, `with_aligned_keyframes`
+        // already sets both to the same value for alpha recordings
+        if self.video_codec == VideoCodec::Vp9
+            && !self.alpha_channel
+            && self.min_keyframe_interval > 0.0
+            && self.min_keyframe_interval != self.keyframe_interval
+        {
+            return Err(crate::Error::Encoding(format!(
+                "VP9 only supports a minimum keyframe interval equal to the maximum, set min_keyframe_interval to 0 or {}",
+                self.keyframe_interval
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Size frames are encoded at for a movie of `width` x `height`,
+    /// a single output dimension keeps the aspect ratio
+    pub fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let scaled = |size: u32, factor: f64| ((size as f64 * factor).round() as u32).max(1);
+        match (self.output_width, self.output_height) {
+            (0, 0) => (
+                scaled(width, self.output_scale),
+                scaled(height, self.output_scale),
+            ),
+            (0, h) => (scaled(width, h as f64 / height as f64), h),
+            (w, 0) => (w, scaled(height, w as f64 / width as f64)),
+            (w, h) => (w, h),
+        }
+    }
+
+    pub fn pixel_art(&self) -> bool {
+        self.pixel_art_scale > 1
+    }
+
+    /// Replaces the scaling and chroma settings for pixel art mode. Color
+    /// stays at full resolution, except for encoders that can't store 4:4:4,
+    /// which get 2x2 block averaged chroma, exact for even factors
+    pub fn with_pixel_art(mut self) -> Self {
+        if !self.pixel_art() {
+            return self;
+        }
+
+        self.output_width = 0;
+        self.output_height = 0;
+        self.output_scale = self.pixel_art_scale as f64;
+        self.scale_filter = ScaleFilter::Nearest;
+        if !self.video_codec.supports_yuv444() {
+            self.chroma_filter = ChromaFilter::Box;
+        } else {
+            self.chroma_subsampling = ChromaSubsampling::Yuv444;
+        }
+        self
+    }
+
+    /// Whether frames go to a lossless intermediate and get encoded in `write_end`
+    pub fn uses_intermediate(&self) -> bool {
+        self.two_pass || self.target_size_mb > 0.0
+    }
+
+    /// Checks the rate control settings against the codec that will be used,
+    /// so bad values are reported before any encoder is opened
+    pub fn validate_rate_control(&self) -> Result<(), crate::Error> {
+        // lossless, there is nothing to control
+        if self.video_codec == VideoCodec::Ffv1 {
+            return Ok(());
+        }
+
+        let max_crf = match self.video_codec {
+            VideoCodec::H264 => 51,
+            _ => 63,
+        };
+
+        let uses_crf = matches!(
+            self.rate_control,
+            RateControl::ConstantQuality | RateControl::ConstrainedQuality
+        );
+        if uses_crf && self.crf() > max_crf {
+            return Err(crate::Error::Encoding(format!(
+                "CRF {} is out of range for {:?}, expected 0-{max_crf}",
+                self.crf(),
+                self.video_codec
+            )));
+        }
+
+        if self.rate_control != RateControl::ConstantQuality && self.bitrate_kbps == 0 {
+            return Err(crate::Error::Encoding(format!(
+                "{:?} rate control needs a bitrate above 0",
+                self.rate_control
+            )));
+        }
+
+        if self.rate_control == RateControl::Vbr {
+            if self.min_bitrate_kbps > 0 && self.min_bitrate_kbps > self.bitrate_kbps {
+                return Err(crate::Error::Encoding(format!(
+                    "Minimum bitrate {} kbps is above the target of {} kbps",
+                    self.min_bitrate_kbps, self.bitrate_kbps
+                )));
+            }
+
+            if self.max_bitrate_kbps > 0 && self.max_bitrate_kbps < self.bitrate_kbps {
+                return Err(crate::Error::Encoding(format!(
+                    "Maximum bitrate {} kbps is below the target of {} kbps",
+                    self.max_bitrate_kbps, self.bitrate_kbps
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn register_project_settings() {
         let mut project_settings = ProjectSettings::singleton();
 
@@ -109,11 +1035,24 @@ impl EncoderConfig {
                 "name": alpha_name.clone(),
                 "type": VariantType::BOOL,
                 "hint": PropertyHint::NONE,
-                "description": "Include alpha channel (transparency) in the file? This will slow down encoding and is only possible if the true."
+                "description": "Include alpha channel (transparency) in the file? The matte is stored as WebM BlockAdditional data so browsers and editors play it back transparent. This will slow down encoding."
             };
             project_settings.add_property_info(alpha_info);
         }
 
+        let alpha_sidecar_name = SETTING_ALPHA_SIDECAR.to_godot();
+        if !project_settings.has_setting(alpha_sidecar_name.clone()) {
+            project_settings.set(alpha_sidecar_name.clone().into(), false.to_variant());
+
+            let alpha_sidecar_info = dict! {
+                "name": alpha_sidecar_name.clone(),
+                "type": VariantType::BOOL,
+                "hint": PropertyHint::NONE,
+                "description": "Legacy mode: write the alpha channel to a separate *_alpha.webm file instead of embedding it in the main file."
+            };
+            project_settings.add_property_info(alpha_sidecar_info);
+        }
+
         let enable_audio_name = SETTING_ENABLE_AUDIO.to_godot();
         if !project_settings.has_setting(enable_audio_name.clone()) {
             project_settings.set(enable_audio_name.clone().into(), true.to_variant());
@@ -127,6 +1066,543 @@ impl EncoderConfig {
             project_settings.add_property_info(enable_audio_info);
         }
 
+        let video_codec_name = SETTING_VIDEO_CODEC.to_godot();
+        if !project_settings.has_setting(video_codec_name.clone()) {
+            project_settings.set(video_codec_name.clone().into(), "VP9".to_variant());
+
+            let video_codec_info = dict! {
+                "name": video_codec_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": video_codec_hint().to_variant(),
+                "description": "Video codec. AV1 requires Sorkin built with the av1 feature and an FFmpeg with libsvtav1 or libaom. FFV1 is lossless and only available when writing .mkv files."
+            };
+            project_settings.add_property_info(video_codec_info);
+        }
+
+        let audio_codec_name = SETTING_AUDIO_CODEC.to_godot();
+        if !project_settings.has_setting(audio_codec_name.clone()) {
+            project_settings.set(audio_codec_name.clone().into(), "Opus".to_variant());
+
+            let audio_codec_info = dict! {
+                "name": audio_codec_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": "Opus,FLAC".to_variant(),
+                "description": "Audio codec. FLAC is lossless and only available when writing .mkv files."
+            };
+            project_settings.add_property_info(audio_codec_info);
+        }
+
+        let mp4_audio_codec_name = SETTING_MP4_AUDIO_CODEC.to_godot();
+        if !project_settings.has_setting(mp4_audio_codec_name.clone()) {
+            project_settings.set(mp4_audio_codec_name.clone().into(), "AAC".to_variant());
+
+            let mp4_audio_codec_info = dict! {
+                "name": mp4_audio_codec_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": "AAC,Opus".to_variant(),
+                "description": "Audio codec used when writing .mp4 or .mov files, which always use H.264 video. Opus is only allowed in .mp4."
+            };
+            project_settings.add_property_info(mp4_audio_codec_info);
+        }
+
+        let speaker_mode_name = SETTING_SPEAKER_MODE.to_godot();
+        if !project_settings.has_setting(speaker_mode_name.clone()) {
+            project_settings.set(speaker_mode_name.clone().into(), "Stereo".to_variant());
+
+            let speaker_mode_info = dict! {
+                "name": speaker_mode_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": "Stereo,5.1,7.1".to_variant(),
+                "description": "Speaker layout Godot mixes the recording in and Sorkin encodes. Movie Maker mixes through a dummy audio driver that always reports stereo, so surround sound has to be picked here."
+            };
+            project_settings.add_property_info(speaker_mode_info);
+        }
+
+        let rate_control_name = SETTING_RATE_CONTROL.to_godot();
+        if !project_settings.has_setting(rate_control_name.clone()) {
+            project_settings.set(
+                rate_control_name.clone().into(),
+                "Constant Quality".to_variant(),
+            );
+
+            let rate_control_info = dict! {
+                "name": rate_control_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": "Constant Quality,Constrained Quality,VBR,CBR".to_variant(),
+                "description": "How the encoder trades file size for quality. Constant quality only uses the CRF, constrained quality also caps the bitrate, VBR and CBR target the bitrate."
+            };
+            project_settings.add_property_info(rate_control_info);
+        }
+
+        let crf_name = SETTING_CRF.to_godot();
+        if !project_settings.has_setting(crf_name.clone()) {
+            project_settings.set(crf_name.clone().into(), (-1i32).to_variant());
+
+            let crf_info = dict! {
+                "name": crf_name.clone(),
+                "type": VariantType::INT.to_variant(),
+                "hint": PropertyHint::RANGE.to_variant(),
+                "hint_string": "-1,63,1".to_variant(),
+                "description": "Constant rate factor, lower values give higher quality and bigger files. VP9 and AV1 accept 0-63, H.264 accepts 0-51. -1 picks a default for the codec, 32 for VP9 and AV1 and 23 for H.264."
+            };
+            project_settings.add_property_info(crf_info);
+        }
+
+        for (name, default, description) in [
+            (
+                SETTING_BITRATE,
+                8000i32,
+                "Target video bitrate for VBR and CBR, and the ceiling for constrained quality.",
+            ),
+            (
+                SETTING_MIN_BITRATE,
+                0,
+                "Lowest video bitrate in VBR mode - 0 leaves it to the encoder",
+            ),
+            (
+                SETTING_MAX_BITRATE,
+                0,
+                "Highest video bitrate in VBR mode - 0 leaves it to the encoder",
+            ),
+        ] {
+            let bitrate_name = name.to_godot();
+            if !project_settings.has_setting(bitrate_name.clone()) {
+                project_settings.set(bitrate_name.clone().into(), default.to_variant());
+
+                let bitrate_info = dict! {
+                    "name": bitrate_name.clone(),
+                    "type": VariantType::INT.to_variant(),
+                    "hint": PropertyHint::RANGE.to_variant(),
+                    "hint_string": "0,100000,1,or_greater,suffix:kbps".to_variant(),
+                    "description": description,
+                };
+                project_settings.add_property_info(bitrate_info);
+            }
+        }
+
+        let two_pass_name = SETTING_TWO_PASS.to_godot();
+        if !project_settings.has_setting(two_pass_name.clone()) {
+            project_settings.set(two_pass_name.clone().into(), false.to_variant());
+
+            let two_pass_info = dict! {
+                "name": two_pass_name.clone(),
+                "type": VariantType::BOOL,
+                "hint": PropertyHint::NONE,
+                "description": "Encode VP9 in two passes for better bitrate distribution. Frames are stored losslessly in a temporary file while recording and encoded when recording ends, which can take a while for long clips."
+            };
+            project_settings.add_property_info(two_pass_info);
+        }
+
+        let target_size_name = SETTING_TARGET_SIZE.to_godot();
+        if !project_settings.has_setting(target_size_name.clone()) {
+            project_settings.set(target_size_name.clone().into(), 0.0f64.to_variant());
+
+            let target_size_info = dict! {
+                "name": target_size_name.clone(),
+                "type": VariantType::FLOAT.to_variant(),
+                "hint": PropertyHint::RANGE.to_variant(),
+                "hint_string": "0,4096,0.1,or_greater,suffix:MB".to_variant(),
+                "description": "Fit the finished file under this size, e.g. 8 for chat upload limits - 0 disables it. Overrides the rate control settings, frames are stored losslessly while recording and encoded to fit when recording ends.",
+            };
+            project_settings.add_property_info(target_size_info);
+        }
+
+        for (name, description) in [
+            (
+                SETTING_KEYFRAME_INTERVAL,
+                "Longest distance between keyframes - 0 leaves it to the encoder. Shorter intervals make seeking and scrubbing in editors faster at the cost of file size.",
+            ),
+            (
+                SETTING_MIN_KEYFRAME_INTERVAL,
+                "Shortest distance between keyframes - 0 leaves it to the encoder. Setting it equal to the maximum places keyframes at a fixed interval, which is the only other value VP9 accepts.",
+            ),
+        ] {
+            let keyframe_name = name.to_godot();
+            if !project_settings.has_setting(keyframe_name.clone()) {
+                project_settings.set(keyframe_name.clone().into(), 0.0f64.to_variant());
+
+                let keyframe_info = dict! {
+                    "name": keyframe_name.clone(),
+                    "type": VariantType::FLOAT.to_variant(),
+                    "hint": PropertyHint::RANGE.to_variant(),
+                    "hint_string": "0,600,0.1,or_greater".to_variant(),
+                    "description": description,
+                };
+                project_settings.add_property_info(keyframe_info);
+            }
+        }
+
+        let keyframe_unit_name = SETTING_KEYFRAME_UNIT.to_godot();
+        if !project_settings.has_setting(keyframe_unit_name.clone()) {
+            project_settings.set(keyframe_unit_name.clone().into(), "Frames".to_variant());
+
+            let keyframe_unit_info = dict! {
+                "name": keyframe_unit_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": "Frames,Seconds".to_variant(),
+                "description": "Unit of the keyframe interval settings."
+            };
+            project_settings.add_property_info(keyframe_unit_info);
+        }
+
+        let scene_cut_name = SETTING_SCENE_CUT_THRESHOLD.to_godot();
+        if !project_settings.has_setting(scene_cut_name.clone()) {
+            project_settings.set(scene_cut_name.clone().into(), 0.0f64.to_variant());
+
+            let scene_cut_info = dict! {
+                "name": scene_cut_name.clone(),
+                "type": VariantType::FLOAT.to_variant(),
+                "hint": PropertyHint::RANGE.to_variant(),
+                "hint_string": "0,1,0.01".to_variant(),
+                "description": "Force a keyframe when the average brightness change between two frames exceeds this fraction, around 0.15 catches hard cuts - 0 disables it.",
+            };
+            project_settings.add_property_info(scene_cut_info);
+        }
+
+        for (name, description) in [
+            (
+                SETTING_VIDEO_OPTIONS,
+                "Extra FFmpeg options for the video encoder, e.g. {\"cpu-used\": 4}. Applied over Sorkin's defaults, rejected options are reported when recording starts.",
+            ),
+            (
+                SETTING_AUDIO_OPTIONS,
+                "Extra FFmpeg options for the audio encoder, e.g. {\"application\": \"voip\"}. Applied over Sorkin's defaults, rejected options are reported when recording starts.",
+            ),
+            (
+                SETTING_MUXER_OPTIONS,
+                "Extra FFmpeg options for the container muxer, e.g. {\"cluster_time_limit\": 1000}. Applied over Sorkin's defaults, rejected options are reported when recording starts.",
+            ),
+        ] {
+            let options_name = name.to_godot();
+            if !project_settings.has_setting(options_name.clone()) {
+                project_settings.set(options_name.clone().into(), Dictionary::new().to_variant());
+
+                let options_info = dict! {
+                    "name": options_name.clone(),
+                    "type": VariantType::DICTIONARY.to_variant(),
+                    "hint": PropertyHint::NONE.to_variant(),
+                    "description": description,
+                };
+                project_settings.add_property_info(options_info);
+            }
+        }
+
+        let preset_name = SETTING_PRESET.to_godot();
+        if !project_settings.has_setting(preset_name.clone()) {
+            project_settings.set(preset_name.clone().into(), "".to_variant());
+
+            let preset_info = dict! {
+                "name": preset_name.clone(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::NONE.to_variant(),
+                "description": format!(
+                    "SorkinPreset resource path, or the name of a built-in preset ({}). Overrides the codec, quality, rate control, audio and alpha settings - leave empty to use the settings above.",
+                    crate::preset::BUILTIN_PRESETS.join(", ")
+                ),
+            };
+            project_settings.add_property_info(preset_info);
+        }
+
+        let bit_depth_name = SETTING_BIT_DEPTH.to_godot();
+        if !project_settings.has_setting(bit_depth_name.clone()) {
+            project_settings.set(bit_depth_name.clone().into(), "8-bit".to_variant());
+
+            let bit_depth_info = dict! {
+                "name": bit_depth_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": "8-bit,10-bit".to_variant(),
+                "description": "Bits per sample. 10-bit avoids banding in gradients and is required for HDR, it needs VP9 (profile 2), AV1 or FFV1."
+            };
+            project_settings.add_property_info(bit_depth_info);
+        }
+
+        let hdr_transfer_name = SETTING_HDR_TRANSFER.to_godot();
+        if !project_settings.has_setting(hdr_transfer_name.clone()) {
+            project_settings.set(hdr_transfer_name.clone().into(), "SDR".to_variant());
+
+            let hdr_transfer_info = dict! {
+                "name": hdr_transfer_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": "SDR,PQ,HLG".to_variant(),
+                "description": "HDR output in BT.2020 with the PQ (HDR10) or HLG transfer, requires 10-bit. Enable HDR 2D on the viewport so Godot hands over unclamped colors, SDR white is placed at 203 nits."
+            };
+            project_settings.add_property_info(hdr_transfer_info);
+        }
+
+        let chroma_subsampling_name = SETTING_CHROMA_SUBSAMPLING.to_godot();
+        if !project_settings.has_setting(chroma_subsampling_name.clone()) {
+            project_settings.set(chroma_subsampling_name.clone().into(), "4:2:0".to_variant());
+
+            let chroma_subsampling_info = dict! {
+                "name": chroma_subsampling_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": "4:2:0,4:4:4".to_variant(),
+                "description": "4:4:4 keeps color at full resolution, which keeps pixel art outlines and colored UI text sharp. Needs VP9 (profile 1), libaom AV1 or FFV1, and not every player supports it."
+            };
+            project_settings.add_property_info(chroma_subsampling_info);
+        }
+
+        let chroma_filter_name = SETTING_CHROMA_FILTER.to_godot();
+        if !project_settings.has_setting(chroma_filter_name.clone()) {
+            project_settings.set(chroma_filter_name.clone().into(), "Bilinear".to_variant());
+
+            let chroma_filter_info = dict! {
+                "name": chroma_filter_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": "Bilinear,Box,Fast".to_variant(),
+                "description": "How color is downsampled for 4:2:0. Bilinear and Box average neighboring pixels, which stops thin moving lines from shimmering. Fast keeps only every other pixel's color."
+            };
+            project_settings.add_property_info(chroma_filter_info);
+        }
+
+        for (name, description) in [
+            (
+                SETTING_OUTPUT_WIDTH,
+                "Width of the encoded video - 0 follows the movie size. With only one of width and height set the other keeps the aspect ratio.",
+            ),
+            (
+                SETTING_OUTPUT_HEIGHT,
+                "Height of the encoded video - 0 follows the movie size. With only one of width and height set the other keeps the aspect ratio.",
+            ),
+        ] {
+            let output_size_name = name.to_godot();
+            if !project_settings.has_setting(output_size_name.clone()) {
+                project_settings.set(output_size_name.clone().into(), 0i32.to_variant());
+
+                let output_size_info = dict! {
+                    "name": output_size_name.clone(),
+                    "type": VariantType::INT.to_variant(),
+                    "hint": PropertyHint::RANGE.to_variant(),
+                    "hint_string": "0,8192,1,or_greater,suffix:px".to_variant(),
+                    "description": description,
+                };
+                project_settings.add_property_info(output_size_info);
+            }
+        }
+
+        let output_scale_name = SETTING_OUTPUT_SCALE.to_godot();
+        if !project_settings.has_setting(output_scale_name.clone()) {
+            project_settings.set(output_scale_name.clone().into(), 1.0f64.to_variant());
+
+            let output_scale_info = dict! {
+                "name": output_scale_name.clone(),
+                "type": VariantType::FLOAT.to_variant(),
+                "hint": PropertyHint::RANGE.to_variant(),
+                "hint_string": "0.05,4,0.05,or_greater".to_variant(),
+                "description": "Scales the movie size when output_width and output_height are 0, e.g. 0.5 to record a 4K render as 1080p.",
+            };
+            project_settings.add_property_info(output_scale_info);
+        }
+
+        let pixel_art_scale_name = SETTING_PIXEL_ART_SCALE.to_godot();
+        if !project_settings.has_setting(pixel_art_scale_name.clone()) {
+            project_settings.set(pixel_art_scale_name.clone().into(), 0i32.to_variant());
+
+            let pixel_art_scale_info = dict! {
+                "name": pixel_art_scale_name.clone(),
+                "type": VariantType::INT.to_variant(),
+                "hint": PropertyHint::RANGE.to_variant(),
+                "hint_string": "0,16,1,suffix:x".to_variant(),
+                "description": "Pixel art mode - upscales the movie by this whole factor with nearest neighbor, keeps color at full resolution and tunes VP9 for screen content, so the pixels survive re-encodes by video platforms. 0 disables it, and it overrides the output size settings.",
+            };
+            project_settings.add_property_info(pixel_art_scale_info);
+        }
+
+        let scale_filter_name = SETTING_SCALE_FILTER.to_godot();
+        if !project_settings.has_setting(scale_filter_name.clone()) {
+            project_settings.set(scale_filter_name.clone().into(), "Lanczos".to_variant());
+
+            let scale_filter_info = dict! {
+                "name": scale_filter_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": "Nearest,Bilinear,Bicubic,Lanczos".to_variant(),
+                "description": "Filter used when the output size differs from the movie size. Lanczos is the sharpest, Nearest keeps hard pixel edges."
+            };
+            project_settings.add_property_info(scale_filter_info);
+        }
+
+        let capture_rect_name = SETTING_CAPTURE_RECT.to_godot();
+        if !project_settings.has_setting(capture_rect_name.clone()) {
+            project_settings.set(
+                capture_rect_name.clone().into(),
+                Rect2i::default().to_variant(),
+            );
+
+            let capture_rect_info = dict! {
+                "name": capture_rect_name.clone(),
+                "type": VariantType::RECT2I.to_variant(),
+                "hint": PropertyHint::NONE.to_variant(),
+                "description": "Only record this region of the movie, e.g. the playfield without the HUD or a 9:16 slice - an empty rect records everything. Its size sets the output size, and set_capture_rect() can move it while recording.",
+            };
+            project_settings.add_property_info(capture_rect_info);
+        }
+
+        let capture_smoothing_name = SETTING_CAPTURE_SMOOTHING.to_godot();
+        if !project_settings.has_setting(capture_smoothing_name.clone()) {
+            project_settings.set(capture_smoothing_name.clone().into(), 0.2f64.to_variant());
+
+            let capture_smoothing_info = dict! {
+                "name": capture_smoothing_name.clone(),
+                "type": VariantType::FLOAT.to_variant(),
+                "hint": PropertyHint::RANGE.to_variant(),
+                "hint_string": "0,2,0.01,or_greater,suffix:s".to_variant(),
+                "description": "How long set_capture_rect() takes to ease into a new region, 0 jumps immediately.",
+            };
+            project_settings.add_property_info(capture_smoothing_info);
+        }
+
+        let fit_mode_name = SETTING_FIT_MODE.to_godot();
+        if !project_settings.has_setting(fit_mode_name.clone()) {
+            project_settings.set(fit_mode_name.clone().into(), "Pad".to_variant());
+
+            let fit_mode_info = dict! {
+                "name": fit_mode_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": "Stretch,Pad,Crop".to_variant(),
+                "description": "What happens when the recorded region doesn't have the output's aspect ratio, e.g. a 16:10 game recorded as 16:9 or a resized window. Pad adds bars in the pad color, Crop cuts off the edges and Stretch distorts the image."
+            };
+            project_settings.add_property_info(fit_mode_info);
+        }
+
+        let pad_color_name = SETTING_PAD_COLOR.to_godot();
+        if !project_settings.has_setting(pad_color_name.clone()) {
+            project_settings.set(pad_color_name.clone().into(), Color::BLACK.to_variant());
+
+            let pad_color_info = dict! {
+                "name": pad_color_name.clone(),
+                "type": VariantType::COLOR.to_variant(),
+                "hint": PropertyHint::NONE.to_variant(),
+                "description": "Color of the bars added by the Pad fit mode. Its alpha is recorded when recording transparency.",
+            };
+            project_settings.add_property_info(pad_color_info);
+        }
+
+        let resize_policy_name = SETTING_RESIZE_POLICY.to_godot();
+        if !project_settings.has_setting(resize_policy_name.clone()) {
+            project_settings.set(resize_policy_name.clone().into(), "Rescale".to_variant());
+
+            let resize_policy_info = dict! {
+                "name": resize_policy_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": "Rescale,New File".to_variant(),
+                "description": "What happens when the window is resized while recording. Rescale keeps the output size and fits the new frames into it as fit_mode says, New File finishes the current file and continues in movie_2.webm, movie_3.webm and so on at the new size."
+            };
+            project_settings.add_property_info(resize_policy_info);
+        }
+
+        let frame_rate_name = SETTING_FRAME_RATE.to_godot();
+        if !project_settings.has_setting(frame_rate_name.clone()) {
+            project_settings.set(frame_rate_name.clone().into(), "".to_variant());
+
+            let frame_rate_info = dict! {
+                "name": frame_rate_name.clone(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::PLACEHOLDER_TEXT.to_variant(),
+                "hint_string": "Movie Maker fps".to_variant(),
+                "description": "Frame rate of the output, e.g. 29.97, 59.94, 23.976 or 30000/1001. Frames are repeated or dropped when it differs from Movie Maker's fixed fps. Leave empty to use Movie Maker's fps.",
+            };
+            project_settings.add_property_info(frame_rate_info);
+        }
+
+        let color_matrix_name = SETTING_COLOR_MATRIX.to_godot();
+        if !project_settings.has_setting(color_matrix_name.clone()) {
+            project_settings.set(color_matrix_name.clone().into(), "BT.709".to_variant());
+
+            let color_matrix_info = dict! {
+                "name": color_matrix_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": "BT.601,BT.709,BT.2020".to_variant(),
+                "description": "Matrix used to convert RGB to YUV, tagged in the file so players convert back with the same one. HDR output always uses BT.2020."
+            };
+            project_settings.add_property_info(color_matrix_info);
+        }
+
+        let color_range_name = SETTING_COLOR_RANGE.to_godot();
+        if !project_settings.has_setting(color_range_name.clone()) {
+            project_settings.set(color_range_name.clone().into(), "Limited".to_variant());
+
+            let color_range_info = dict! {
+                "name": color_range_name.to_variant(),
+                "type": VariantType::STRING.to_variant(),
+                "hint": PropertyHint::ENUM.to_variant(),
+                "hint_string": "Limited,Full".to_variant(),
+                "description": "Limited (16-235) is what players and editors expect. Full range keeps a little more precision but some players ignore the tag."
+            };
+            project_settings.add_property_info(color_range_info);
+        }
+
         godot_print!("Sorkin encoder settings registered in Editor Settings under Sorkin category");
     }
 }
+
+const MAX_FRAME_RATE_TERM: i32 = 1_000_000;
+
+/// Parses "30000/1001", "29.97" or "60". Decimal NTSC rates like 23.976,
+/// 29.97 and 59.94 stand for their exact x/1.001 fractions.
+pub fn parse_frame_rate(text: &str) -> Option<ffmpeg_next::Rational> {
+    let text = text.trim();
+    let rate = match text.split_once('/') {
+        Some((num, den)) => {
+            ffmpeg_next::Rational::new(num.trim().parse().ok()?, den.trim().parse().ok()?)
+        }
+        None => {
+            let fps = text.parse::<f64>().ok().filter(|fps| fps.is_finite())?;
+            let ntsc = (fps * 1.001).round();
+            if fps.fract() != 0.0 && (ntsc / 1.001 - fps).abs() < 0.005 {
+                ffmpeg_next::Rational::new(ntsc as i32 * 1000, 1001)
+            } else {
+                ffmpeg_next::Rational::from(fps)
+            }
+        }
+    };
+
+    // Keeps the stream time base, a thousandth of a frame, within an i32
+    let rate = rate
+        .reduce_with_limit(MAX_FRAME_RATE_TERM)
+        .unwrap_or_else(|closest| closest);
+    (rate.numerator() > 0 && rate.denominator() > 0).then_some(rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(text: &str) -> Option<(i32, i32)> {
+        parse_frame_rate(text).map(|rate| (rate.numerator(), rate.denominator()))
+    }
+
+    #[test]
+    fn ntsc_rates_are_exact() {
+        assert_eq!(terms("29.97"), Some((30000, 1001)));
+        assert_eq!(terms("59.94"), Some((60000, 1001)));
+        assert_eq!(terms("23.976"), Some((24000, 1001)));
+    }
+
+    #[test]
+    fn other_rates_keep_their_value() {
+        assert_eq!(terms("25.5"), Some((51, 2)));
+        assert_eq!(terms("60"), Some((60, 1)));
+        assert_eq!(terms(" 120/2 "), Some((60, 1)));
+    }
+
+    #[test]
+    fn invalid_rates_are_rejected() {
+        for text in ["30/0", "-30", "0", "", "fast", "30/-1"] {
+            assert_eq!(terms(text), None, "{text:?} was accepted");
+        }
+    }
+}
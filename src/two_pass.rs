@@ -0,0 +1,663 @@
+//! Two-pass encoding for Movie Maker renders. While recording, frames and
+//! audio are stored losslessly in a temporary Matroska file, `write_end` then
+//! replays that file once to gather first pass statistics and once more for
+//! the real encode. Target size mode uses the same file, once the duration
+//! is known it picks a bitrate and re-encodes until the output fits.
+use std::{collections::VecDeque, ffi::CStr, mem::size_of, path::PathBuf};
+
+use ffmpeg_next::{self as ffmpeg, codec::Id, encoder, frame, picture};
+use godot::engine::audio_server::SpeakerMode;
+
+use crate::{
+    audio, conversion,
+    log::{log_print, log_warn},
+    settings::{EncoderConfig, Quality, RateControl, VideoCodec},
+    Error, OutputEncoder,
+};
+
+/// Which pass an encoder is configured for
+#[derive(Clone, Copy, Debug)]
+pub enum Pass<'a> {
+    Single,
+    First,
+    /// Carries the statistics gathered by the first pass
+    Second(&'a str),
+}
+
+/// First pass statistics for the main stream and the matte
+pub struct PassStats {
+    pub video: String,
+    pub alpha: Option<String>,
+}
+
+/// Upload limits are given in decimal megabytes
+pub const BYTES_PER_MB: f64 = 1_000_000.0;
+/// Encodes tried before giving up on fitting the target size
+pub const TARGET_SIZE_ATTEMPTS: u32 = 3;
+/// Below this VP9 output is unwatchable, better to miss the target
+const MIN_TARGET_BITRATE_KBPS: f64 = 50.0;
+/// Share of the budget kept for container overhead and rate control overshoot
+const TARGET_SIZE_HEADROOM: f64 = 0.95;
+
+fn video_streams(config: &EncoderConfig) -> f64 {
+    if config.alpha_channel {
+        2.0
+    } else {
+        1.0
+    }
+}
+
+/// Switches `config` to VBR at the bitrate that fits `duration` seconds into
+/// `target_size_mb`, split evenly between the main and alpha streams
+pub fn fit_to_size(config: &mut EncoderConfig, duration: f64, audio_channels: u16) {
+    let total_kbps = config.target_size_mb * BYTES_PER_MB * 8.0 / 1000.0 / duration.max(0.001)
+        * TARGET_SIZE_HEADROOM;
+    let audio_kbps = if config.enable_audio {
+        audio::stream_bitrate(config.audio_codec, audio_channels).unwrap_or(0) as f64 / 1000.0
+    } else {
+        0.0
+    };
+    let video_kbps =
+        ((total_kbps - audio_kbps) / video_streams(config)).max(MIN_TARGET_BITRATE_KBPS);
+
+    config.rate_control = RateControl::Vbr;
+    config.bitrate_kbps = video_kbps as u32;
+    config.min_bitrate_kbps = 0;
+    config.max_bitrate_kbps = 0;
+}
+
+/// Lowers the bitrate of a `fit_to_size` config after an encode came out
+/// `size` bytes big, returns false when it can't go any lower
+pub fn shrink_to_size(config: &mut EncoderConfig, duration: f64, size: u64) -> bool {
+    let target = config.target_size_mb * BYTES_PER_MB * TARGET_SIZE_HEADROOM;
+    let excess_kbps = (size as f64 - target).max(0.0) * 8.0 / 1000.0 / duration.max(0.001);
+    let video_kbps = (config.bitrate_kbps as f64 - excess_kbps / video_streams(config))
+        .max(MIN_TARGET_BITRATE_KBPS) as u32;
+
+    if video_kbps >= config.bitrate_kbps {
+        return false;
+    }
+    config.bitrate_kbps = video_kbps;
+    true
+}
+
+/// Writes converted frames and audio to a lossless temporary file
+pub struct Intermediate {
+    output: ffmpeg::format::context::Output,
+    path: PathBuf,
+    video: encoder::Video,
+    alpha: Option<(encoder::Video, usize)>,
+    audio: Option<(encoder::Audio, usize)>,
+    audio_channels: u16,
+    audio_samples: i64,
+    frame_count: usize,
+    /// Indices of frames flagged as keyframes while recording
+    forced_keyframes: Vec<usize>,
+    fps: ffmpeg::Rational,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Intermediate {
+    pub fn new(
+        width: u32,
+        height: u32,
+        fps: ffmpeg::Rational,
+        speaker_mode: SpeakerMode,
+        config: &EncoderConfig,
+    ) -> Result<Self, Error> {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let path = std::env::temp_dir().join(format!("sorkin_{}_{nanos}.mkv", std::process::id()));
+
+        log_print!("Storing two-pass intermediate at {}", path.display());
+        let mut output = ffmpeg::format::output(&path)?;
+
+        let global_header = output
+            .format()
+            .flags()
+            .contains(ffmpeg::format::Flags::GLOBAL_HEADER);
+
+        let ffv1 = encoder::find(Id::FFV1)
+            .ok_or_else(|| Error::Encoding("FFV1 codec not found".to_string()))?;
+
+        // Fastest FFV1 settings, this file only lives until `write_end`
+        let lossless = EncoderConfig {
+            video_codec: VideoCodec::Ffv1,
+            quality: Quality::Realtime,
+            // The matte is kept in its own stream for the replay
+            alpha_sidecar: true,
+            video_options: Vec::new(),
+            ..config.clone()
+        };
+        let open_video = || {
+            OutputEncoder::configure_encoder(
+                ffv1,
+                width,
+                height,
+                fps,
+                global_header,
+                &lossless,
+                Pass::Single,
+            )
+        };
+
+        let video = open_video()?;
+        {
+            let mut stream = output.add_stream(ffv1)?;
+            stream.set_time_base(video.time_base());
+            stream.set_parameters(&video);
+        }
+
+        let alpha = if config.alpha_channel {
+            let alpha = open_video()?;
+            let mut stream = output.add_stream(ffv1)?;
+            stream.set_time_base(alpha.time_base());
+            stream.set_parameters(&alpha);
+            Some((alpha, stream.index()))
+        } else {
+            None
+        };
+
+        let audio_channels = audio::channel_count(speaker_mode);
+        let audio = if config.enable_audio {
+            let pcm = encoder::find(Id::PCM_F32LE)
+                .ok_or_else(|| Error::Encoding("PCM codec not found".to_string()))?;
+
+            let mut encoder = ffmpeg::codec::context::Context::new_with_codec(pcm)
+                .encoder()
+                .audio()
+                .map_err(|e| Error::Encoding(format!("Could not create PCM encoder: {e}")))?;
+            encoder.set_rate(audio::OPUS_SAMPLE_RATE as i32);
+            encoder.set_time_base((1, audio::OPUS_SAMPLE_RATE as i32));
+            encoder.set_format(ffmpeg::format::Sample::F32(
+                ffmpeg::format::sample::Type::Packed,
+            ));
+            encoder.set_channel_layout(
+                audio::channel_layout(audio_channels)
+                    .ok_or_else(|| Error::Encoding("Unsupported channel count".to_string()))?,
+            );
+            let encoder = encoder
+                .open_as(pcm)
+                .map_err(|e| Error::Encoding(format!("Failed to open PCM encoder: {e}")))?;
+
+            let mut stream = output.add_stream(pcm)?;
+            stream.set_time_base(encoder.time_base());
+            stream.set_parameters(&encoder);
+            Some((encoder, stream.index()))
+        } else {
+            None
+        };
+
+        output.write_header()?;
+
+        Ok(Self {
+            output,
+            path,
+            video,
+            alpha,
+            audio,
+            audio_channels,
+            audio_samples: 0,
+            frame_count: 0,
+            forced_keyframes: Vec::new(),
+            fps,
+            width,
+            height,
+        })
+    }
+
+    pub fn time_base(&self) -> ffmpeg::Rational {
+        self.video.time_base()
+    }
+
+    pub fn write_frame(
+        &mut self,
+        frame: &frame::Video,
+        alpha_frame: Option<&frame::Video>,
+    ) -> Result<(), Error> {
+        if frame.kind() == picture::Type::I {
+            self.forced_keyframes.push(self.frame_count);
+        }
+
+        self.video.send_frame(frame)?;
+        drain(&mut self.video, &mut self.output, 0)?;
+
+        if let (Some((alpha, index)), Some(alpha_frame)) = (self.alpha.as_mut(), alpha_frame) {
+            alpha.send_frame(alpha_frame)?;
+            drain(alpha, &mut self.output, *index)?;
+        }
+
+        self.frame_count += 1;
+        Ok(())
+    }
+
+    /// Appends interleaved samples, PCM has no fixed frame size so any length works
+    pub fn write_audio(&mut self, samples: &[f32]) -> Result<(), Error> {
+        let Some((encoder, index)) = self.audio.as_mut() else {
+            return Ok(());
+        };
+
+        let samples_per_channel = samples.len() / self.audio_channels as usize;
+        if samples_per_channel == 0 {
+            return Ok(());
+        }
+
+        let mut frame = frame::Audio::new(
+            encoder.format(),
+            samples_per_channel,
+            encoder.channel_layout(),
+        );
+        frame.set_rate(audio::OPUS_SAMPLE_RATE);
+        frame.set_pts(Some(self.audio_samples));
+
+        let bytes = samples_per_channel * self.audio_channels as usize * size_of::<f32>();
+        let frame_data = frame.data_mut(0);
+        for (dst, sample) in frame_data[..bytes]
+            .chunks_exact_mut(size_of::<f32>())
+            .zip(samples)
+        {
+            dst.copy_from_slice(&sample.to_ne_bytes());
+        }
+
+        encoder.send_frame(&frame)?;
+        drain(encoder, &mut self.output, *index)?;
+
+        self.audio_samples += samples_per_channel as i64;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<IntermediateFile, Error> {
+        self.video.send_eof()?;
+        drain(&mut self.video, &mut self.output, 0)?;
+
+        if let Some((alpha, index)) = self.alpha.as_mut() {
+            alpha.send_eof()?;
+            drain(alpha, &mut self.output, *index)?;
+        }
+
+        if let Some((audio, index)) = self.audio.as_mut() {
+            audio.send_eof()?;
+            drain(audio, &mut self.output, *index)?;
+        }
+
+        self.output.write_trailer()?;
+
+        Ok(IntermediateFile {
+            path: self.path,
+            frame_count: self.frame_count,
+            forced_keyframes: self.forced_keyframes,
+            fps: self.fps,
+            width: self.width,
+            height: self.height,
+            audio_channels: self.audio_channels,
+        })
+    }
+}
+
+fn drain(
+    encoder: &mut encoder::Encoder,
+    output: &mut ffmpeg::format::context::Output,
+    stream_index: usize,
+) -> Result<(), Error> {
+    let mut packet = ffmpeg::packet::Packet::empty();
+
+    while encoder.receive_packet(&mut packet).is_ok() {
+        packet.set_stream(stream_index);
+        packet.rescale_ts(
+            encoder.time_base(),
+            output.stream(stream_index).unwrap().time_base(),
+        );
+        packet.write_interleaved(output)?;
+    }
+
+    Ok(())
+}
+
+/// Something read back from the intermediate file, in recording order
+pub enum Replayed {
+    Video(frame::Video, Option<frame::Video>),
+    Audio(Vec<f32>),
+}
+
+enum StreamDecoder {
+    Video(ffmpeg::decoder::Video),
+    Alpha(ffmpeg::decoder::Video),
+    Audio(ffmpeg::decoder::Audio),
+}
+
+/// A finished intermediate, deleted when dropped
+pub struct IntermediateFile {
+    path: PathBuf,
+    pub frame_count: usize,
+    forced_keyframes: Vec<usize>,
+    fps: ffmpeg::Rational,
+    pub width: u32,
+    pub height: u32,
+    pub audio_channels: u16,
+}
+
+impl IntermediateFile {
+    /// Decodes the file from the start, handing every frame pair and audio
+    /// block to `on_item`. Frames are stamped in the time base the output
+    /// encoders are opened with, the decoded ones are in Matroska's
+    pub fn replay(
+        &self,
+        mut on_item: impl FnMut(Replayed) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let mut input = ffmpeg::format::input(&self.path)?;
+
+        let mut decoders = Vec::new();
+        let mut has_alpha = false;
+        for stream in input.streams() {
+            let context = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?;
+            let decoder = match stream.parameters().medium() {
+                ffmpeg::media::Type::Video if decoders.is_empty() => {
+                    StreamDecoder::Video(context.decoder().video()?)
+                }
+                ffmpeg::media::Type::Video => {
+                    has_alpha = true;
+                    StreamDecoder::Alpha(context.decoder().video()?)
+                }
+                _ => StreamDecoder::Audio(context.decoder().audio()?),
+            };
+            decoders.push(decoder);
+        }
+
+        let mut video_frames = VecDeque::new();
+        let mut alpha_frames = VecDeque::new();
+        let mut video_index = 0;
+
+        let mut receive = |decoder: &mut StreamDecoder,
+                           on_item: &mut dyn FnMut(Replayed) -> Result<(), Error>|
+         -> Result<(), Error> {
+            match decoder {
+                StreamDecoder::Video(decoder) => {
+                    let mut frame = frame::Video::empty();
+                    while decoder.receive_frame(&mut frame).is_ok() {
+                        video_frames.push_back(frame);
+                        frame = frame::Video::empty();
+                    }
+                }
+                StreamDecoder::Alpha(decoder) => {
+                    let mut frame = frame::Video::empty();
+                    while decoder.receive_frame(&mut frame).is_ok() {
+                        alpha_frames.push_back(frame);
+                        frame = frame::Video::empty();
+                    }
+                }
+                StreamDecoder::Audio(decoder) => {
+                    let mut frame = frame::Audio::empty();
+                    while decoder.receive_frame(&mut frame).is_ok() {
+                        let len = frame.samples() * self.audio_channels as usize;
+                        let samples = frame.data(0)[..len * size_of::<f32>()]
+                            .chunks_exact(size_of::<f32>())
+                            .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+                            .collect();
+                        on_item(Replayed::Audio(samples))?;
+                    }
+                }
+            }
+
+            while !video_frames.is_empty() && (!has_alpha || !alpha_frames.is_empty()) {
+                // FFV1 frames all decode as intra, only keep the requested keyframes
+                let kind = if self.forced_keyframes.binary_search(&video_index).is_ok() {
+                    picture::Type::I
+                } else {
+                    picture::Type::None
+                };
+                let pts = conversion::frame_to_pts(
+                    video_index as i64,
+                    self.fps,
+                    conversion::video_time_base(self.fps),
+                );
+                video_index += 1;
+
+                let mut video: frame::Video = video_frames.pop_front().unwrap();
+                video.set_kind(kind);
+                video.set_pts(Some(pts));
+                let mut alpha: Option<frame::Video> = alpha_frames.pop_front();
+                if let Some(ref mut alpha) = alpha {
+                    alpha.set_kind(kind);
+                    alpha.set_pts(Some(pts));
+                }
+                on_item(Replayed::Video(video, alpha))?;
+            }
+
+            Ok(())
+        };
+
+        for (stream, packet) in input.packets() {
+            let decoder = &mut decoders[stream.index()];
+            match decoder {
+                StreamDecoder::Video(d) | StreamDecoder::Alpha(d) => d.send_packet(&packet)?,
+                StreamDecoder::Audio(d) => d.send_packet(&packet)?,
+            }
+            receive(decoder, &mut on_item)?;
+        }
+
+        for decoder in decoders.iter_mut() {
+            match decoder {
+                StreamDecoder::Video(d) | StreamDecoder::Alpha(d) => d.send_eof()?,
+                StreamDecoder::Audio(d) => d.send_eof()?,
+            }
+            receive(decoder, &mut on_item)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for IntermediateFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log_warn!(
+                "Failed to remove two-pass intermediate {}: {e}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+/// Encoders that only produce first pass statistics, their packets are discarded
+pub struct FirstPass {
+    video: encoder::Video,
+    alpha: Option<encoder::Video>,
+}
+
+impl FirstPass {
+    pub fn new(
+        width: u32,
+        height: u32,
+        fps: ffmpeg::Rational,
+        config: &EncoderConfig,
+    ) -> Result<Self, Error> {
+        let codec = config.video_codec.find_encoder().ok_or_else(|| {
+            Error::Encoding(format!(
+                "No {:?} encoder in this FFmpeg build",
+                config.video_codec
+            ))
+        })?;
+
+        let open = |config: &EncoderConfig| {
+            OutputEncoder::configure_encoder(codec, width, height, fps, false, config, Pass::First)
+        };
+
+        Ok(Self {
+            video: open(config)?,
+            alpha: if config.alpha_channel {
+                Some(open(&config.matte_config())?)
+            } else {
+                None
+            },
+        })
+    }
+
+    pub fn write_frame(
+        &mut self,
+        frame: &frame::Video,
+        alpha_frame: Option<&frame::Video>,
+    ) -> Result<(), Error> {
+        self.video.send_frame(frame)?;
+        discard_packets(&mut self.video);
+
+        if let (Some(alpha), Some(alpha_frame)) = (self.alpha.as_mut(), alpha_frame) {
+            alpha.send_frame(alpha_frame)?;
+            discard_packets(alpha);
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<PassStats, Error> {
+        let video = finish_stats(&mut self.video)?;
+        let alpha = match self.alpha.as_mut() {
+            Some(alpha) => Some(finish_stats(alpha)?),
+            None => None,
+        };
+
+        Ok(PassStats { video, alpha })
+    }
+}
+
+fn discard_packets(encoder: &mut encoder::Video) {
+    let mut packet = ffmpeg::packet::Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {}
+}
+
+/// libvpx only fills `stats_out` once it has been flushed, with the whole
+/// log base64 encoded, so it is read a single time after EOF
+fn finish_stats(encoder: &mut encoder::Video) -> Result<String, Error> {
+    encoder.send_eof()?;
+    discard_packets(encoder);
+
+    let stats = unsafe {
+        let stats_out = (*encoder.as_ptr()).stats_out;
+        if stats_out.is_null() {
+            return Err(Error::Encoding(
+                "Encoder produced no first pass statistics".to_string(),
+            ));
+        }
+        CStr::from_ptr(stats_out).to_string_lossy().into_owned()
+    };
+
+    Ok(stats)
+}
+
+/// Reports progress of the offline passes every 5%
+pub struct Progress {
+    label: &'static str,
+    total: usize,
+    done: usize,
+    last_percent: usize,
+}
+
+impl Progress {
+    pub fn new(label: &'static str, total: usize) -> Self {
+        log_print!("Sorkin {label}: encoding {total} frames");
+        Self {
+            label,
+            total,
+            done: 0,
+            last_percent: 0,
+        }
+    }
+
+    pub fn step(&mut self) {
+        self.done += 1;
+        let percent = self.done * 100 / self.total.max(1);
+
+        if percent >= self.last_percent + 5 || self.done == self.total {
+            self.last_percent = percent;
+            log_print!(
+                "Sorkin {}: {percent}% ({}/{} frames)",
+                self.label,
+                self.done,
+                self.total
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE_SECONDS: f64 = 60.0;
+    const HOUR_SECONDS: f64 = 60.0 * 60.0;
+
+    fn target_config(
+        target_size_mb: f64,
+        enable_audio: bool,
+        alpha_channel: bool,
+    ) -> EncoderConfig {
+        EncoderConfig {
+            target_size_mb,
+            enable_audio,
+            alpha_channel,
+            ..EncoderConfig::default()
+        }
+    }
+
+    #[test]
+    fn video_gets_the_budget_minus_headroom() {
+        // 8 MB in a minute is 1066.67 kbps, 95% of it is 1013.33
+        let mut config = target_config(8.0, false, false);
+        fit_to_size(&mut config, MINUTE_SECONDS, 2);
+        assert_eq!(config.rate_control, RateControl::Vbr);
+        assert_eq!(config.bitrate_kbps, 1013);
+        assert_eq!((config.min_bitrate_kbps, config.max_bitrate_kbps), (0, 0));
+    }
+
+    #[test]
+    fn audio_is_subtracted_from_the_budget() {
+        let mut config = target_config(8.0, true, false);
+        fit_to_size(&mut config, MINUTE_SECONDS, 2);
+        assert_eq!(config.bitrate_kbps, 1013 - 128);
+
+        // 5.1 Opus takes 64 kbps per channel
+        let mut config = target_config(8.0, true, false);
+        fit_to_size(&mut config, MINUTE_SECONDS, 6);
+        assert_eq!(config.bitrate_kbps, 1013 - 384);
+    }
+
+    #[test]
+    fn alpha_splits_the_video_budget() {
+        let mut config = target_config(8.0, true, true);
+        fit_to_size(&mut config, MINUTE_SECONDS, 2);
+        assert_eq!(config.bitrate_kbps, (1013 - 128) / 2);
+    }
+
+    #[test]
+    fn long_clips_keep_the_minimum_bitrate() {
+        let mut config = target_config(1.0, true, false);
+        fit_to_size(&mut config, HOUR_SECONDS, 2);
+        assert_eq!(config.bitrate_kbps, MIN_TARGET_BITRATE_KBPS as u32);
+    }
+
+    #[test]
+    fn shrinking_removes_the_excess() {
+        let mut config = target_config(8.0, false, false);
+        fit_to_size(&mut config, MINUTE_SECONDS, 2);
+
+        // 1.4 MB over the 7.6 MB after headroom is 186.67 kbps too much
+        assert!(shrink_to_size(&mut config, MINUTE_SECONDS, 9_000_000));
+        assert_eq!(config.bitrate_kbps, 826);
+
+        let mut config = target_config(8.0, false, true);
+        fit_to_size(&mut config, MINUTE_SECONDS, 2);
+        assert_eq!(config.bitrate_kbps, 506);
+        assert!(shrink_to_size(&mut config, MINUTE_SECONDS, 9_000_000));
+        assert_eq!(config.bitrate_kbps, 412);
+    }
+
+    #[test]
+    fn shrinking_stops_at_the_minimum_bitrate() {
+        let mut config = target_config(1.0, false, false);
+        fit_to_size(&mut config, HOUR_SECONDS, 2);
+        assert!(!shrink_to_size(&mut config, HOUR_SECONDS, 10_000_000));
+        assert_eq!(config.bitrate_kbps, MIN_TARGET_BITRATE_KBPS as u32);
+    }
+}